@@ -68,7 +68,10 @@ fn main() -> Result<()> {
                 if price_1.chain_id != price_2.chain_id && p1.item_code < 1000000 {
                     continue;
                 }
-                let ratio = p1.item_price.parse::<f64>()? / p2.item_price.parse::<f64>()?;
+                let (Some(price_a), Some(price_b)) = (p1.item_price, p2.item_price) else {
+                    continue;
+                };
+                let ratio = price_a.0 as f64 / price_b.0 as f64;
                 if ratio.is_infinite() {
                     println!(
                         "Infinite {} - {}, {} - {},",
@@ -5,11 +5,17 @@ use axum::{
     http::StatusCode,
     response::{Html, IntoResponse, Response},
     routing::get,
-    Router,
+    Json, Router,
 };
+use chrono::NaiveDate;
+use israel_prices::cities;
+use israel_prices::geocoding::{distance_km, Location};
 use israel_prices::models;
-use rusqlite::{params, Connection};
-use serde::Deserialize;
+use israel_prices::price::Price;
+use itertools::Itertools;
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -24,9 +30,15 @@ async fn main() {
         .route("/compare/:store_1/:store_2", get(compare))
         .route("/", get(index))
         .route("/stores", get(stores))
+        .route("/stores/near", get(stores_near))
         .route("/search/:query", get(search))
         .route("/searchproduct/:query", get(searchproduct))
         .route("/product/:barcode", get(product))
+        .route("/product/:barcode/history", get(product_history))
+        .route(
+            "/product/:barcode/history/chart",
+            get(product_history_chart),
+        )
         .route("/store/:chain_id/:store_id", get(store));
     let port = std::env::args().nth(1).unwrap_or("3000".to_string());
     tracing::debug!("listening on http://0.0.0.0:{port}");
@@ -36,9 +48,11 @@ async fn main() {
         .unwrap();
 }
 
+const DATA_PATH: &str = "data.sqlite";
+const HISTORY_PATH: &str = "history.sqlite";
+
 fn connection() -> Result<Connection> {
-    let path = "data.sqlite";
-    Ok(rusqlite::Connection::open(path)?)
+    Ok(rusqlite::Connection::open(DATA_PATH)?)
 }
 
 async fn index() -> Result<impl IntoResponse, AppError> {
@@ -46,9 +60,16 @@ async fn index() -> Result<impl IntoResponse, AppError> {
         std::fs::read_to_string("templates/index.html").unwrap_or("Error".to_string()),
     ))
 }
-async fn stores() -> Result<impl IntoResponse, AppError> {
+#[derive(Deserialize)]
+struct StoresParams {
+    // The CBS code of the city, 0 for all the cities.
+    city: Option<u32>,
+}
+
+async fn stores(params: extract::Query<StoresParams>) -> Result<impl IntoResponse, AppError> {
+    let city = params.city.filter(|id| *id != 0);
     let connection = connection()?;
-    let mut stmt = connection.prepare("SELECT subchains.ChainId, subchains.SubchainId, ChainName, SubchainName, StoreName, StoreId, City FROM Stores JOIN Subchains on Stores.chainId = Subchains.chainId AND Stores.subchainid = Subchains.subchainid")?;
+    let mut stmt = connection.prepare("SELECT subchains.ChainId, subchains.SubchainId, ChainName, SubchainName, StoreName, StoreId, City FROM Stores JOIN Subchains on Stores.chainId = Subchains.chainId AND Stores.subchainid = Subchains.subchainid WHERE ?1 IS NULL OR CityId = ?1")?;
     #[derive(Debug)]
     struct StoreRow {
         chain_id: i64,
@@ -59,7 +80,7 @@ async fn stores() -> Result<impl IntoResponse, AppError> {
         store_id: i64,
         city: String,
     }
-    let mut result = stmt.query(())?;
+    let mut result = stmt.query(params![city])?;
     let mut stores = Vec::new();
     while let Some(row) = result.next()? {
         stores.push(StoreRow {
@@ -72,16 +93,112 @@ async fn stores() -> Result<impl IntoResponse, AppError> {
             city: row.get(6)?,
         });
     }
+
+    let mut stmt =
+        connection.prepare("SELECT DISTINCT CityId FROM Stores WHERE CityId IS NOT NULL")?;
+    let city_ids = stmt
+        .query_map((), |row| row.get::<_, u32>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let cities = city_ids
+        .into_iter()
+        .filter_map(cities::by_id)
+        .sorted_by_key(|city| city.hebrew)
+        .collect();
+
     #[derive(Template)]
     #[template(path = "stores.html")]
     struct StoresTemplate {
         stores: Vec<StoreRow>,
+        cities: Vec<&'static cities::City>,
+        selected_city: u32,
     }
-    let template = StoresTemplate { stores };
+    let template = StoresTemplate {
+        stores,
+        cities,
+        selected_city: city.unwrap_or(0),
+    };
 
     Ok(HtmlTemplate(template))
 }
 
+fn default_radius() -> f64 {
+    5.0
+}
+
+#[derive(Deserialize)]
+struct NearParams {
+    lat: f64,
+    lon: f64,
+    // In kilometers.
+    #[serde(default = "default_radius")]
+    radius: f64,
+}
+
+#[derive(Serialize)]
+struct NearStore {
+    chain_id: models::ChainId,
+    subchain_id: models::SubchainId,
+    chain_name: String,
+    store_id: models::StoreId,
+    store_name: String,
+    address: String,
+    city: String,
+    latitude: f64,
+    longitude: f64,
+    distance_km: f64,
+}
+
+async fn stores_near(params: extract::Query<NearParams>) -> Result<impl IntoResponse, AppError> {
+    let center = Location {
+        latitude: params.lat,
+        longitude: params.lon,
+    };
+    // A degree of latitude is about 111km, a degree of longitude gets shorter away from the
+    // equator.
+    let lat_delta = params.radius / 111.0;
+    let lon_delta = params.radius / (111.0 * params.lat.to_radians().cos().abs().max(0.01));
+    let connection = connection()?;
+    let mut stmt = connection.prepare(
+        "
+    SELECT
+        Stores.ChainId, Stores.SubchainId, ChainName, StoreId, StoreName, Address, City, Latitude, Longitude
+    FROM Stores JOIN Subchains ON Stores.ChainId = Subchains.ChainId AND Stores.SubchainId = Subchains.SubchainId
+    WHERE Latitude BETWEEN ?1 AND ?2 AND Longitude BETWEEN ?3 AND ?4
+    ",
+    )?;
+    let mut result = stmt.query(params![
+        params.lat - lat_delta,
+        params.lat + lat_delta,
+        params.lon - lon_delta,
+        params.lon + lon_delta
+    ])?;
+    let mut stores = Vec::new();
+    while let Some(row) = result.next()? {
+        let location = Location {
+            latitude: row.get(7)?,
+            longitude: row.get(8)?,
+        };
+        let distance = distance_km(center, location);
+        if distance > params.radius {
+            continue;
+        }
+        stores.push(NearStore {
+            chain_id: row.get(0)?,
+            subchain_id: row.get(1)?,
+            chain_name: row.get(2)?,
+            store_id: row.get(3)?,
+            store_name: row.get(4)?,
+            address: row.get(5)?,
+            city: row.get(6)?,
+            latitude: location.latitude,
+            longitude: location.longitude,
+            distance_km: distance,
+        });
+    }
+    stores.sort_by(|a, b| a.distance_km.total_cmp(&b.distance_km));
+    Ok(Json(stores))
+}
+
 async fn search(
     extract::Path(query): extract::Path<String>,
 ) -> Result<impl IntoResponse, AppError> {
@@ -176,14 +293,17 @@ async fn product(
     struct ItemRecord {
         name: String,
         description: String,
+        // The names other chains use for the item.
+        also_known_as: Vec<String>,
     }
-    let item = {
+    let mut item = {
         let mut items = Vec::new();
 
         while let Some(row) = result.next()? {
             items.push(ItemRecord {
                 name: row.get(0)?,
                 description: row.get(1)?,
+                also_known_as: Vec::new(),
             });
         }
         if items.len() != 1 {
@@ -191,6 +311,11 @@ async fn product(
         }
         items.remove(0)
     };
+    let mut stmt =
+        connection.prepare("SELECT ItemName FROM ItemAlternativeNames WHERE ItemCode = ?1;")?;
+    item.also_known_as = stmt
+        .query_map(params![product_id], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
 
     let mut stmt = connection.prepare(
         "
@@ -207,24 +332,312 @@ async fn product(
     let mut stores = Vec::new();
     struct StoreRecord {
         price: String,
+        effective_price: String,
+        regular_price: Option<Price>,
+        best_price: Option<Price>,
+        chain_id: models::ChainId,
+        store_id: models::StoreId,
         chain_name: String,
         store_name: String,
+        promotions: Vec<String>,
     }
     let mut result: rusqlite::Rows<'_> = stmt.query(params![product_id])?;
     while let Some(row) = result.next()? {
+        let price: Option<Price> = row.get(0)?;
+        let price_text = price.map_or(String::new(), |p| p.to_string());
         stores.push(StoreRecord {
-            price: row.get(0)?,
+            effective_price: price_text.clone(),
+            price: price_text,
+            regular_price: price,
+            best_price: price,
+            chain_id: row.get(1)?,
+            store_id: row.get(2)?,
             chain_name: row.get(3)?,
             store_name: row.get(4)?,
+            promotions: Vec::new(),
         });
     }
+
+    let mut stmt = connection.prepare(
+        "
+    SELECT
+        promotions.chainid, promotions.storeid, promotions.description,
+        promotions.discountedprice, promotions.discountrate, promotions.minqty, promotions.clubonly
+    FROM promotions JOIN promotionitems
+    ON
+        promotions.chainid = promotionitems.chainid and
+        promotions.subchainid = promotionitems.subchainid and
+        promotions.storeid = promotionitems.storeid and
+        promotions.promotionid = promotionitems.promotionid
+    WHERE
+        promotionitems.itemcode = ?1 and
+        promotionitems.isgift = 0 and
+        (promotions.startdate is null or promotions.startdate <= datetime('now', 'localtime')) and
+        (promotions.enddate is null or promotions.enddate >= datetime('now', 'localtime'));
+    ",
+    )?;
+    let mut result: rusqlite::Rows<'_> = stmt.query(params![product_id])?;
+    while let Some(row) = result.next()? {
+        let chain_id: models::ChainId = row.get(0)?;
+        let store_id: models::StoreId = row.get(1)?;
+        let description: String = row.get(2)?;
+        let discounted_price: Option<Price> = row.get(3)?;
+        let discount_rate: Option<f64> = row.get(4)?;
+        let min_qty: Option<f64> = row.get(5)?;
+        let club_only: bool = row.get(6)?;
+        for store in stores
+            .iter_mut()
+            .filter(|s| s.chain_id == chain_id && s.store_id == store_id)
+        {
+            match club_only {
+                true => store.promotions.push(format!("{description} (מועדון)")),
+                false => {
+                    store.promotions.push(description.clone());
+                    let effective_price = store.regular_price.and_then(|price| {
+                        effective_price(price, discounted_price, discount_rate, min_qty)
+                    });
+                    if let Some(effective_price) = effective_price {
+                        if store.best_price.is_none_or(|best| effective_price < best) {
+                            store.best_price = Some(effective_price);
+                            store.effective_price = effective_price.to_string();
+                        }
+                    }
+                }
+            }
+        }
+    }
     #[derive(Template)]
     #[template(path = "product.html")]
     struct ProductTemplate {
+        barcode: String,
         item: ItemRecord,
         stores: Vec<StoreRecord>,
     }
-    let template = ProductTemplate { item, stores };
+    let template = ProductTemplate {
+        barcode: product_id,
+        item,
+        stores,
+    };
+    Ok(HtmlTemplate(template))
+}
+
+// The discounted price is the price of the whole bundle when the promotion has a minimal
+// quantity, e.g. "2 ב-10" has a discounted price of 10 and a MinQty of 2, and the price of a
+// single unit otherwise.
+fn effective_price(
+    price: Price,
+    discounted_price: Option<Price>,
+    discount_rate: Option<f64>,
+    min_qty: Option<f64>,
+) -> Option<Price> {
+    if let Some(discounted_price) = discounted_price.filter(|p| p.0 > 0) {
+        return match min_qty.filter(|qty| *qty > 1.0) {
+            Some(min_qty) => Some(Price((discounted_price.0 as f64 / min_qty).round() as i64)),
+            None => Some(discounted_price),
+        };
+    }
+    // Rates are usually percents, but some chains publish them in hundredths of percent.
+    let rate = discount_rate.filter(|rate| *rate > 0.0)?;
+    let rate = if rate > 100.0 { rate / 100.0 } else { rate };
+    Some(Price((price.0 as f64 * (1.0 - rate / 100.0)).round() as i64))
+}
+
+#[derive(Serialize)]
+struct PricePoint {
+    date: NaiveDate,
+    min: f64,
+    median: f64,
+    max: f64,
+}
+
+#[derive(Serialize)]
+struct ChainPriceHistory {
+    chain_id: models::ChainId,
+    chain_name: String,
+    points: Vec<PricePoint>,
+}
+
+// history.sqlite only contains price changes, so the price of each store on a given day is
+// the last one seen up to that day.
+fn get_price_history(
+    history_path: &str,
+    data_path: &str,
+    barcode: &str,
+) -> Result<Vec<ChainPriceHistory>> {
+    let history_connection =
+        rusqlite::Connection::open_with_flags(history_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = history_connection.prepare(
+        "
+    SELECT ChainId, StoreId, ItemPrice, FileDate
+    FROM PriceHistory
+    WHERE ItemCode = ?1
+    ORDER BY FileDate;
+    ",
+    )?;
+    struct Change {
+        chain_id: models::ChainId,
+        store_id: models::StoreId,
+        price: Option<f64>,
+        date: NaiveDate,
+    }
+    let mut changes = Vec::new();
+    let mut result = stmt.query(params![barcode])?;
+    while let Some(row) = result.next()? {
+        let price: Option<Price> = row.get(2)?;
+        let date: String = row.get(3)?;
+        changes.push(Change {
+            chain_id: row.get(0)?,
+            store_id: row.get(1)?,
+            price: price.filter(|p| p.0 > 0).map(|p| p.0 as f64 / 100.0),
+            date: NaiveDate::parse_from_str(date.get(..10).unwrap_or(""), "%Y-%m-%d")?,
+        });
+    }
+
+    let chain_names = get_chain_names(data_path).unwrap_or_else(|e| {
+        info!("Showing chain ids instead of names: {e}");
+        HashMap::new()
+    });
+
+    let mut current_prices: BTreeMap<(models::ChainId, models::StoreId), f64> = BTreeMap::new();
+    let mut histories: BTreeMap<models::ChainId, Vec<PricePoint>> = BTreeMap::new();
+    for (date, changes) in &changes.into_iter().group_by(|change| change.date) {
+        for change in changes {
+            match change.price {
+                Some(price) => current_prices.insert((change.chain_id, change.store_id), price),
+                None => current_prices.remove(&(change.chain_id, change.store_id)),
+            };
+        }
+        for (chain_id, prices) in &current_prices
+            .iter()
+            .group_by(|((chain_id, _), _)| *chain_id)
+        {
+            let prices = prices
+                .map(|(_, price)| *price)
+                .sorted_by(|a, b| a.total_cmp(b))
+                .collect::<Vec<f64>>();
+            let median = match prices.len() % 2 {
+                0 => (prices[prices.len() / 2 - 1] + prices[prices.len() / 2]) / 2.0,
+                _ => prices[prices.len() / 2],
+            };
+            histories.entry(chain_id).or_default().push(PricePoint {
+                date,
+                min: prices[0],
+                median,
+                max: prices[prices.len() - 1],
+            });
+        }
+    }
+
+    Ok(histories
+        .into_iter()
+        .map(|(chain_id, points)| ChainPriceHistory {
+            chain_id,
+            chain_name: chain_names
+                .get(&chain_id)
+                .cloned()
+                .unwrap_or(chain_id.to_string()),
+            points,
+        })
+        .collect())
+}
+
+// The history can be shown without data.sqlite, which is only needed for the chain names.
+fn get_chain_names(data_path: &str) -> Result<HashMap<models::ChainId, String>> {
+    let connection =
+        rusqlite::Connection::open_with_flags(data_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = connection.prepare("SELECT ChainId, ChainName FROM Chains")?;
+    let mut result = stmt.query(())?;
+    let mut chain_names = HashMap::new();
+    while let Some(row) = result.next()? {
+        chain_names.insert(row.get(0)?, row.get(1)?);
+    }
+    Ok(chain_names)
+}
+
+async fn product_history(
+    extract::Path(barcode): extract::Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(Json(get_price_history(HISTORY_PATH, DATA_PATH, &barcode)?))
+}
+
+async fn product_history_chart(
+    extract::Path(barcode): extract::Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    const WIDTH: f64 = 800.0;
+    const HEIGHT: f64 = 400.0;
+    const MARGIN: f64 = 50.0;
+    const COLORS: [&str; 8] = [
+        "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f",
+    ];
+
+    let histories = get_price_history(HISTORY_PATH, DATA_PATH, &barcode)?;
+    let points = || histories.iter().flat_map(|h| h.points.iter());
+    let first_date = points().map(|p| p.date).min();
+    let last_date = points().map(|p| p.date).max();
+    let min_price = points().map(|p| p.min).reduce(f64::min).unwrap_or(0.0);
+    let max_price = points().map(|p| p.max).reduce(f64::max).unwrap_or(0.0);
+
+    let days = match (first_date, last_date) {
+        (Some(first), Some(last)) => (last - first).num_days().max(1) as f64,
+        _ => 1.0,
+    };
+    let price_range = (max_price - min_price).max(0.01);
+    let x = |date: NaiveDate| {
+        let day = first_date.map_or(0, |first| (date - first).num_days()) as f64;
+        MARGIN + day / days * (WIDTH - 2.0 * MARGIN)
+    };
+    let y =
+        |price: f64| HEIGHT - MARGIN - (price - min_price) / price_range * (HEIGHT - 2.0 * MARGIN);
+    let polyline = |points: &Vec<PricePoint>, value: fn(&PricePoint) -> f64| {
+        points
+            .iter()
+            .map(|p| format!("{:.1},{:.1}", x(p.date), y(value(p))))
+            .join(" ")
+    };
+
+    struct ChartLine {
+        chain_name: String,
+        color: &'static str,
+        min: String,
+        median: String,
+        max: String,
+    }
+    let lines = histories
+        .iter()
+        .enumerate()
+        .map(|(i, history)| ChartLine {
+            chain_name: history.chain_name.clone(),
+            color: COLORS[i % COLORS.len()],
+            min: polyline(&history.points, |p| p.min),
+            median: polyline(&history.points, |p| p.median),
+            max: polyline(&history.points, |p| p.max),
+        })
+        .collect();
+
+    #[derive(Template)]
+    #[template(path = "price_history.html")]
+    struct PriceHistoryTemplate {
+        barcode: String,
+        width: f64,
+        height: f64,
+        margin: f64,
+        first_date: String,
+        last_date: String,
+        min_price: String,
+        max_price: String,
+        lines: Vec<ChartLine>,
+    }
+    let template = PriceHistoryTemplate {
+        barcode,
+        width: WIDTH,
+        height: HEIGHT,
+        margin: MARGIN,
+        first_date: first_date.map_or(String::new(), |d| d.to_string()),
+        last_date: last_date.map_or(String::new(), |d| d.to_string()),
+        min_price: format!("{min_price:.2}"),
+        max_price: format!("{max_price:.2}"),
+        lines,
+    };
     Ok(HtmlTemplate(template))
 }
 
@@ -261,7 +674,9 @@ async fn store(
     while let Some(row) = result.next()? {
         items.push(Item {
             name: row.get(0)?,
-            price: row.get(1)?,
+            price: row
+                .get::<_, Option<Price>>(1)?
+                .map_or(String::new(), |p| p.to_string()),
         });
     }
 
@@ -397,3 +812,68 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_effective_price() {
+        // A discounted price per unit, or a discount rate.
+        assert_eq!(
+            effective_price(Price(1200), Some(Price(990)), None, None),
+            Some(Price(990))
+        );
+        assert_eq!(
+            effective_price(Price(1200), Some(Price(990)), None, Some(1.0)),
+            Some(Price(990))
+        );
+        assert_eq!(
+            effective_price(Price(1200), None, Some(25.0), None),
+            Some(Price(900))
+        );
+        assert_eq!(
+            effective_price(Price(1200), None, Some(2500.0), None),
+            Some(Price(900))
+        );
+        assert_eq!(effective_price(Price(1200), None, None, None), None);
+        // "2 ב-10" on an item of 12 costs 5 a unit, and "2 ב-30" on an item of 12 costs 15.
+        assert_eq!(
+            effective_price(Price(1200), Some(Price(1000)), None, Some(2.0)),
+            Some(Price(500))
+        );
+        assert_eq!(
+            effective_price(Price(1200), Some(Price(3000)), None, Some(2.0)),
+            Some(Price(1500))
+        );
+    }
+
+    #[test]
+    fn test_get_price_history() {
+        let history_path = concat!(env!("CARGO_MANIFEST_DIR"), "/test_data/history.sqlite");
+        let histories = get_price_history(history_path, "missing.sqlite", "7290000000017").unwrap();
+        assert_eq!(
+            serde_json::to_value(&histories).unwrap(),
+            serde_json::json!([
+                {
+                    "chain_id": 7290027600007i64,
+                    "chain_name": "7290027600007",
+                    "points": [
+                        {"date": "2023-06-10", "min": 5.9, "median": 6.2, "max": 6.5},
+                        {"date": "2023-06-11", "min": 4.9, "median": 5.7, "max": 6.5},
+                        {"date": "2023-06-12", "min": 6.5, "median": 6.5, "max": 6.5},
+                    ],
+                },
+                {
+                    "chain_id": 7290058140886i64,
+                    "chain_name": "7290058140886",
+                    "points": [
+                        {"date": "2023-06-11", "min": 5.5, "median": 5.5, "max": 5.5},
+                        {"date": "2023-06-12", "min": 5.5, "median": 5.5, "max": 5.5},
+                    ],
+                },
+            ])
+        );
+        assert!(get_price_history("missing.sqlite", "missing.sqlite", "7290000000017").is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+
+use crate::geocoding::normalize_name;
+
+// A locality, identified by its CBS code (סמל יישוב).
+#[derive(Debug, PartialEq)]
+pub struct City {
+    pub id: u32,
+    pub hebrew: &'static str,
+    pub english: &'static str,
+}
+
+const fn city(id: u32, hebrew: &'static str, english: &'static str) -> City {
+    City {
+        id,
+        hebrew,
+        english,
+    }
+}
+
+lazy_static! {
+    static ref CITIES: Vec<City> = vec![
+        city(3000, "ירושלים", "Jerusalem"),
+        city(5000, "תל אביב - יפו", "Tel Aviv - Yafo"),
+        city(4000, "חיפה", "Haifa"),
+        city(8300, "ראשון לציון", "Rishon LeZion"),
+        city(7900, "פתח תקווה", "Petah Tikva"),
+        city(70, "אשדוד", "Ashdod"),
+        city(7400, "נתניה", "Netanya"),
+        city(9000, "באר שבע", "Beer Sheva"),
+        city(6100, "בני ברק", "Bnei Brak"),
+        city(6600, "חולון", "Holon"),
+        city(8600, "רמת גן", "Ramat Gan"),
+        city(8400, "רחובות", "Rehovot"),
+        city(7100, "אשקלון", "Ashkelon"),
+        city(6200, "בת ים", "Bat Yam"),
+        city(2610, "בית שמש", "Beit Shemesh"),
+        city(6900, "כפר סבא", "Kfar Saba"),
+        city(6400, "הרצליה", "Herzliya"),
+        city(6500, "חדרה", "Hadera"),
+        city(1200, "מודיעין-מכבים-רעות", "Modiin-Maccabim-Reut"),
+        city(7300, "נצרת", "Nazareth"),
+        city(7000, "לוד", "Lod"),
+        city(8500, "רמלה", "Ramla"),
+        city(8700, "רעננה", "Raanana"),
+        city(3797, "מודיעין עילית", "Modiin Illit"),
+        city(1161, "רהט", "Rahat"),
+        city(9700, "הוד השרון", "Hod HaSharon"),
+        city(6300, "גבעתיים", "Givatayim"),
+        city(6800, "קרית אתא", "Kiryat Ata"),
+        city(9100, "נהריה", "Nahariya"),
+        city(3780, "ביתר עילית", "Beitar Illit"),
+        city(2710, "אום אל-פחם", "Umm al-Fahm"),
+        city(2630, "קרית גת", "Kiryat Gat"),
+        city(2600, "אילת", "Eilat"),
+        city(2640, "ראש העין", "Rosh HaAyin"),
+        city(7700, "עפולה", "Afula"),
+        city(7200, "נס ציונה", "Nes Ziona"),
+        city(7600, "עכו", "Akko"),
+        city(1309, "אלעד", "Elad"),
+        city(2650, "רמת השרון", "Ramat HaSharon"),
+        city(1139, "כרמיאל", "Karmiel"),
+        city(2660, "יבנה", "Yavne"),
+        city(6700, "טבריה", "Tiberias"),
+        city(2730, "טייבה", "Tayibe"),
+        city(8200, "קרית מוצקין", "Kiryat Motzkin"),
+        city(8800, "שפרעם", "Shfaram"),
+        city(1061, "נוף הגליל", "Nof HaGalil"),
+        city(9600, "קרית ים", "Kiryat Yam"),
+        city(9500, "קרית ביאליק", "Kiryat Bialik"),
+        city(2620, "קרית אונו", "Kiryat Ono"),
+        city(3616, "מעלה אדומים", "Maale Adumim"),
+        city(2400, "אור יהודה", "Or Yehuda"),
+        city(2200, "דימונה", "Dimona"),
+        city(8000, "צפת", "Safed"),
+        city(1031, "שדרות", "Sderot"),
+        city(246, "נתיבות", "Netivot"),
+        city(31, "אופקים", "Ofakim"),
+        city(2560, "ערד", "Arad"),
+        city(240, "יקנעם עילית", "Yokneam Illit"),
+        city(2800, "קרית שמונה", "Kiryat Shmona"),
+        city(3570, "אריאל", "Ariel"),
+        city(874, "מגדל העמק", "Migdal HaEmek"),
+        city(1020, "אור עקיבא", "Or Akiva"),
+        city(2550, "גדרה", "Gedera"),
+        city(1015, "מבשרת ציון", "Mevaseret Zion"),
+        city(1034, "קרית מלאכי", "Kiryat Malakhi"),
+        city(2100, "טירת כרמל", "Tirat Carmel"),
+        city(9400, "יהוד-מונוסון", "Yehud-Monosson"),
+        city(168, "כפר יונה", "Kfar Yona"),
+        city(9300, "זכרון יעקב", "Zikhron Yaakov"),
+        city(9200, "בית שאן", "Beit Shean"),
+        city(1063, "מעלות-תרשיחא", "Maalot-Tarshiha"),
+        city(2300, "קרית טבעון", "Kiryat Tivon"),
+        city(1304, "שוהם", "Shoham"),
+        city(229, "גני תקווה", "Ganei Tikva"),
+        city(182, "אבן יהודה", "Even Yehuda"),
+        city(7800, "פרדס חנה-כרכור", "Pardes Hanna-Karkur"),
+        city(195, "קדימה-צורן", "Kadima-Zoran"),
+        city(154, "תל מונד", "Tel Mond"),
+        city(2530, "באר יעקב", "Beer Yaakov"),
+    ];
+
+    // Normalized spellings of the cities, as written by the chains.
+    static ref VARIANT_TO_CITY_ID: HashMap<String, u32> = {
+        let mut map = HashMap::new();
+        for city in CITIES.iter() {
+            map.insert(key(city.hebrew), city.id);
+            map.insert(key(city.english), city.id);
+        }
+
+        // Tel Aviv
+        map.insert(key("תל אביב"), 5000);
+        map.insert(key("ת\"א"), 5000);
+        map.insert(key("ת\"א יפו"), 5000);
+        map.insert(key("יפו"), 5000);
+        map.insert(key("Tel Aviv"), 5000);
+        // Abbreviations
+        map.insert(key("י-ם"), 3000);
+        map.insert(key("ב\"ש"), 9000);
+        map.insert(key("ראשל\"צ"), 8300);
+        map.insert(key("ראשון"), 8300);
+        map.insert(key("פ\"ת"), 7900);
+        map.insert(key("פתח תקוה"), 7900);
+        map.insert(key("ב\"ב"), 6100);
+        map.insert(key("ר\"ג"), 8600);
+        map.insert(key("רמת-גן"), 8600);
+        map.insert(key("כ\"ס"), 6900);
+        map.insert(key("נהרייה"), 9100);
+        map.insert(key("הרצלייה"), 6400);
+        // Modiin
+        map.insert(key("מודיעין"), 1200);
+        map.insert(key("מודיעין מכבים רעות"), 1200);
+        map.insert(key("Modiin"), 1200);
+        map.insert(key("נצרת עילית"), 1061);
+        map.insert(key("אום אל פאחם"), 2710);
+        map.insert(key("טייבה במשולש"), 2730);
+        map.insert(key("שפר עם"), 8800);
+        map.insert(key("Acre"), 7600);
+        map.insert(key("Zefat"), 8000);
+        map.insert(key("יקנעם"), 240);
+        map.insert(key("יהוד"), 9400);
+        map.insert(key("מעלות"), 1063);
+        map.insert(key("פרדס חנה"), 7800);
+        map.insert(key("קדימה"), 195);
+        map
+    };
+}
+
+// Hyphens, quotes and case don't matter: "קריית-גת" and "קרית גת" are the same city.
+fn key(name: &str) -> String {
+    normalize_name(name).to_lowercase()
+}
+
+pub fn by_id(id: u32) -> Option<&'static City> {
+    CITIES.iter().find(|city| city.id == id)
+}
+
+// The city written as a name, or as a CBS code by some chains.
+pub fn find(name: &str) -> Option<&'static City> {
+    let name = name.trim();
+    if let Ok(id) = name.parse::<u32>() {
+        return by_id(id);
+    }
+    VARIANT_TO_CITY_ID.get(&key(name)).and_then(|id| by_id(*id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        for variant in [
+            "תל אביב",
+            "ת\"א",
+            "תל אביב-יפו",
+            "תל-אביב יפו",
+            "5000",
+            "TEL AVIV",
+        ] {
+            assert_eq!(find(variant).map(|c| c.id), Some(5000), "{variant}");
+        }
+        assert_eq!(find("קריית גת").unwrap().english, "Kiryat Gat");
+        assert_eq!(find("פ\u{05F4}ת").unwrap().hebrew, "פתח תקווה");
+        assert_eq!(find("לא עיר"), None);
+        assert_eq!(find("123456"), None);
+        // Every variant points to a known city.
+        for id in VARIANT_TO_CITY_ID.values() {
+            assert!(by_id(*id).is_some(), "{id}");
+        }
+    }
+}
@@ -1,8 +1,11 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use itertools::Itertools;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
 
+use crate::models::ChainId;
+
 #[derive(Default, Debug)]
 pub struct DataCounter<K> {
     elems: HashMap<K, usize>,
@@ -18,6 +21,13 @@ where
         self.size += 1;
     }
 
+    pub fn merge(&mut self, other: DataCounter<K>) {
+        for (k, count) in other.elems {
+            *self.elems.entry(k).or_insert(0) += count;
+        }
+        self.size += other.size;
+    }
+
     // Remove any elements that occur less than 10% of the times.
     // pub fn remove_rare_elements(self: &mut Self) {
     //     self.elems.retain(|_, value| value >= &mut (self.size / 10));
@@ -41,12 +51,276 @@ where
             .map(|pair| pair.0)
             .ok_or(anyhow!("no value is most_common"))?)
     }
+
+    pub fn count(&self, k: &K) -> usize {
+        self.elems.get(k).copied().unwrap_or(0)
+    }
+}
+
+// Counts the values of a field, and the sum of the weights of their occurrences, so that
+// values from more reliable chains can win, see CanonicalPolicy::Weighted.
+#[derive(Default, Debug)]
+pub struct WeightedCounter {
+    counter: DataCounter<String>,
+    weights: HashMap<String, f64>,
+}
+
+impl WeightedCounter {
+    pub fn inc_weighted(&mut self, k: String, weight: f64) {
+        *self.weights.entry(k.clone()).or_insert(0.0) += weight;
+        self.counter.inc(k);
+    }
+
+    pub fn merge(&mut self, other: WeightedCounter) {
+        self.counter.merge(other.counter);
+        for (k, weight) in other.weights {
+            *self.weights.entry(k).or_insert(0.0) += weight;
+        }
+    }
+
+    pub fn values(&self) -> std::collections::hash_map::Keys<'_, String, usize> {
+        self.counter.values()
+    }
+
+    pub fn count(&self, k: &String) -> usize {
+        self.counter.count(k)
+    }
+
+    pub fn weight(&self, k: &String) -> f64 {
+        self.weights.get(k).copied().unwrap_or(0.0)
+    }
+}
+
+// How the canonical value of a field is chosen among the values the chains use for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CanonicalPolicy {
+    MostCommon,
+    Longest,
+    MostCommonNonEmpty,
+    // The value with the most occurrences, each weighted by the reliability of its chain.
+    Weighted,
+}
+
+impl CanonicalPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CanonicalPolicy::MostCommon => "most-common",
+            CanonicalPolicy::Longest => "longest",
+            CanonicalPolicy::MostCommonNonEmpty => "most-common-non-empty",
+            CanonicalPolicy::Weighted => "weighted",
+        }
+    }
+
+    // Ties are broken by the number of occurrences, then by the value itself, so that the
+    // choice doesn't depend on the order of the files.
+    pub fn select<'a>(&self, counter: &'a WeightedCounter) -> Result<&'a String> {
+        let by_count = |a: &&String, b: &&String| {
+            counter
+                .count(a)
+                .cmp(&counter.count(b))
+                .then_with(|| b.cmp(a))
+        };
+        let non_empty = || counter.values().filter(|v| !v.trim().is_empty());
+        let selected = match self {
+            CanonicalPolicy::MostCommon => counter.values().max_by(by_count),
+            CanonicalPolicy::Longest => return longest(counter),
+            CanonicalPolicy::MostCommonNonEmpty => non_empty().max_by(by_count),
+            CanonicalPolicy::Weighted => non_empty().max_by(|a, b| {
+                counter
+                    .weight(a)
+                    .partial_cmp(&counter.weight(b))
+                    .unwrap_or(Ordering::Equal)
+                    .then_with(|| by_count(a, b))
+            }),
+        };
+        // Values that are all empty are kept empty.
+        selected
+            .or_else(|| counter.values().next())
+            .ok_or(anyhow!("Got unexpectedly no value!"))
+    }
+}
+
+impl std::str::FromStr for CanonicalPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [
+            CanonicalPolicy::MostCommon,
+            CanonicalPolicy::Longest,
+            CanonicalPolicy::MostCommonNonEmpty,
+            CanonicalPolicy::Weighted,
+        ]
+        .into_iter()
+        .find(|policy| policy.as_str() == s)
+        .ok_or(anyhow!("Unknown policy {s}"))
+    }
+}
+
+// The fields of ItemInfo chosen among the values of the chains.
+pub const FIELDS: &[&str] = &[
+    "item_name",
+    "manufacturer_name",
+    "manufacturer_item_description",
+    "manufacture_country",
+    "unit_qty",
+    "quantity",
+    "unit_of_measure",
+    "qty_in_package",
+];
+
+// The policy of each field. Names are weighted, so that the names of the chains with
+// complete names win over truncated ones.
+#[derive(Debug, Clone)]
+pub struct CanonicalPolicies {
+    policies: HashMap<&'static str, CanonicalPolicy>,
+}
+
+impl CanonicalPolicies {
+    // Overrides of the defaults, written as field=policy, e.g. "item_name=longest".
+    pub fn parse(overrides: &[String]) -> Result<CanonicalPolicies> {
+        let mut policies = FIELDS
+            .iter()
+            .map(|field| (*field, CanonicalPolicy::MostCommonNonEmpty))
+            .collect::<HashMap<_, _>>();
+        policies.insert("item_name", CanonicalPolicy::Weighted);
+        for s in overrides {
+            let Some((field, policy)) = s.split_once('=') else {
+                bail!("Expected field=policy, got {s}");
+            };
+            let Some(field) = FIELDS.iter().find(|f| **f == field) else {
+                bail!(
+                    "Unknown field {field}, expected one of {}",
+                    FIELDS.join(", ")
+                );
+            };
+            policies.insert(field, policy.parse()?);
+        }
+        Ok(CanonicalPolicies { policies })
+    }
+
+    pub fn get(&self, field: &str) -> CanonicalPolicy {
+        self.policies[field]
+    }
+
+    pub fn select<'a>(&self, field: &str, counter: &'a WeightedCounter) -> Result<&'a String> {
+        self.get(field).select(counter)
+    }
+
+    // The policy of each field, as stored with the items.
+    pub fn to_map(&self) -> BTreeMap<String, String> {
+        self.policies
+            .iter()
+            .map(|(field, policy)| (field.to_string(), policy.as_str().to_string()))
+            .collect()
+    }
+}
+
+// Values from these chains count more with the weighted policy, as their files have complete
+// names and descriptions, where other chains cut them.
+const RELIABLE_CHAINS: &[(ChainId, f64)] = &[
+    (7290027600007, 2.0), // Shufersal
+    (7290058140886, 2.0), // Rami Levy
+];
+
+// The weight of the values of each chain, 1 for the chains not listed.
+#[derive(Debug, Clone)]
+pub struct ChainWeights {
+    weights: HashMap<ChainId, f64>,
+}
+
+impl ChainWeights {
+    // Overrides of the defaults, written as chain_id=weight, e.g. "7290027600007=3".
+    pub fn parse(overrides: &[String]) -> Result<ChainWeights> {
+        let mut weights = RELIABLE_CHAINS.iter().copied().collect::<HashMap<_, _>>();
+        for s in overrides {
+            let Some((chain_id, weight)) = s.split_once('=') else {
+                bail!("Expected chain_id=weight, got {s}");
+            };
+            let weight = weight.parse::<f64>()?;
+            if weight.is_nan() || weight <= 0.0 {
+                bail!("Expected a positive weight, got {s}");
+            }
+            weights.insert(chain_id.parse()?, weight);
+        }
+        Ok(ChainWeights { weights })
+    }
+
+    pub fn get(&self, chain_id: ChainId) -> f64 {
+        self.weights.get(&chain_id).copied().unwrap_or(1.0)
+    }
+}
+
+// The values other than the chosen one, most common first.
+pub fn alternatives(counter: &WeightedCounter, chosen: &str) -> Vec<String> {
+    counter
+        .values()
+        .filter(|v| *v != chosen && !v.trim().is_empty())
+        .sorted_by(|a, b| counter.count(b).cmp(&counter.count(a)).then(a.cmp(b)))
+        .cloned()
+        .collect()
 }
 
-pub fn longest(counter: &DataCounter<String>) -> Result<&String> {
+pub fn longest(counter: &WeightedCounter) -> Result<&String> {
     counter
         .values()
-        .sorted_by(|a, b| Ord::cmp(&a.len(), &b.len()))
-        .next()
+        .max_by(|a, b| {
+            Ord::cmp(&a.chars().count(), &b.chars().count())
+                .then_with(|| counter.count(a).cmp(&counter.count(b)))
+                .then_with(|| b.cmp(a))
+        })
         .ok_or(anyhow!("Got unexpectedly no value!"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonical_policies() {
+        let mut names = WeightedCounter::default();
+        names.inc_weighted("שוקולד ריטר ספורט חל".to_string(), 1.0);
+        names.inc_weighted("שוקולד ריטר ספורט חל".to_string(), 1.0);
+        names.inc_weighted("".to_string(), 1.0);
+        names.inc_weighted("".to_string(), 1.0);
+        names.inc_weighted("".to_string(), 1.0);
+        names.inc_weighted("שוקולד ריטר ספורט חלב 100 גרם".to_string(), 3.0);
+        names.inc_weighted("ריטר".to_string(), 1.0);
+
+        let select = |policy: CanonicalPolicy| policy.select(&names).unwrap().as_str();
+        assert_eq!(select(CanonicalPolicy::MostCommon), "");
+        assert_eq!(
+            select(CanonicalPolicy::MostCommonNonEmpty),
+            "שוקולד ריטר ספורט חל"
+        );
+        assert_eq!(
+            select(CanonicalPolicy::Longest),
+            "שוקולד ריטר ספורט חלב 100 גרם"
+        );
+        assert_eq!(
+            select(CanonicalPolicy::Weighted),
+            "שוקולד ריטר ספורט חלב 100 גרם"
+        );
+        assert_eq!(
+            alternatives(&names, "שוקולד ריטר ספורט חלב 100 גרם"),
+            vec!["שוקולד ריטר ספורט חל", "ריטר"]
+        );
+
+        let mut empty = WeightedCounter::default();
+        empty.inc_weighted(String::new(), 1.0);
+        assert_eq!(CanonicalPolicy::Weighted.select(&empty).unwrap(), "");
+
+        let policies = CanonicalPolicies::parse(&["quantity=longest".to_string()]).unwrap();
+        assert_eq!(policies.get("quantity"), CanonicalPolicy::Longest);
+        assert_eq!(policies.get("item_name"), CanonicalPolicy::Weighted);
+        assert!(CanonicalPolicies::parse(&["name=longest".to_string()]).is_err());
+        assert!(CanonicalPolicies::parse(&["quantity=shortest".to_string()]).is_err());
+        assert_eq!(policies.to_map()["quantity"], "longest");
+
+        let weights = ChainWeights::parse(&["7290027600007=3".to_string()]).unwrap();
+        assert_eq!(weights.get(7290027600007), 3.0);
+        assert_eq!(weights.get(7290058140886), 2.0);
+        assert_eq!(weights.get(7290696200003), 1.0);
+        assert!(ChainWeights::parse(&["shufersal=3".to_string()]).is_err());
+        assert!(ChainWeights::parse(&["7290027600007=-1".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use anyhow::{anyhow, Result};
+use flate2::read::MultiGzDecoder;
+use serde::Serialize;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Compression {
+    Gzip,
+    Zip,
+    Plain,
+}
+
+// Chains don't always use the extension matching the content of their files, e.g. some serve
+// zip archives named .gz, so the format is detected from the first bytes.
+pub fn detect(start: &[u8]) -> Compression {
+    match start {
+        [0x1f, 0x8b, ..] => Compression::Gzip,
+        [b'P', b'K', 0x03, 0x04, ..] => Compression::Zip,
+        _ => Compression::Plain,
+    }
+}
+
+pub fn decompress(content: &[u8]) -> Result<(Compression, Vec<u8>)> {
+    let compression = detect(content);
+    let mut out = Vec::new();
+    match compression {
+        Compression::Gzip => {
+            MultiGzDecoder::new(content).read_to_end(&mut out)?;
+        }
+        Compression::Zip => {
+            let mut archive = zip::ZipArchive::new(Cursor::new(content))?;
+            if archive.len() != 1 {
+                warn!("Zip archive with {} files, using the first", archive.len());
+            }
+            let file = (0..archive.len())
+                .find(|&i| archive.by_index(i).is_ok_and(|f| f.is_file()))
+                .ok_or(anyhow!("Empty zip archive"))?;
+            archive.by_index(file)?.read_to_end(&mut out)?;
+        }
+        Compression::Plain => out.extend_from_slice(content),
+    }
+    Ok((compression, out))
+}
+
+// The path of a file once decompressed: a.gz -> a, b.zip -> b.
+pub fn decompressed_path(path: &Path) -> PathBuf {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("gz" | "zip" | "GZ" | "ZIP") => path.with_extension(""),
+        _ => path.to_path_buf(),
+    }
+}
+
+// Writes a downloaded file, decompressing it first when needed.
+pub fn write_decompressed(content: &[u8], dest: &Path) -> Result<PathBuf> {
+    let (compression, content) = match decompress(content) {
+        Ok(decompressed) => decompressed,
+        // The raw file is kept, so that curation reports the error.
+        Err(e) => {
+            warn!("Couldn't decompress {}: {e}", dest.display());
+            (Compression::Plain, content.to_vec())
+        }
+    };
+    let dest = match compression {
+        Compression::Plain => dest.to_path_buf(),
+        _ => decompressed_path(dest),
+    };
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&dest, content)?;
+    Ok(dest)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Outcome {
+    Kept,
+    Decompressed(Compression),
+    DeletedEmpty,
+    DeletedX1,
+    Failed(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileOutcome {
+    pub path: String,
+    pub outcome: Outcome,
+}
+
+fn curate_file(path: &Path) -> Result<Outcome> {
+    if path.extension().is_some_and(|e| e == "x1") {
+        std::fs::remove_file(path)?;
+        return Ok(Outcome::DeletedX1);
+    }
+    let mut start = [0; 4];
+    let read = std::fs::File::open(path)?.read(&mut start)?;
+    if read == 0 {
+        std::fs::remove_file(path)?;
+        return Ok(Outcome::DeletedEmpty);
+    }
+    let compression = detect(&start[..read]);
+    let dest = decompressed_path(path);
+    if compression == Compression::Plain && dest == path {
+        return Ok(Outcome::Kept);
+    }
+    let (compression, content) = decompress(&std::fs::read(path)?)?;
+    std::fs::write(&dest, content)?;
+    if dest != path {
+        std::fs::remove_file(path)?;
+    }
+    Ok(Outcome::Decompressed(compression))
+}
+
+// Deletes the files that can't be used, and decompresses the other ones in place.
+pub fn curate_dir(dir: &Path) -> Vec<FileOutcome> {
+    let outcomes: Vec<FileOutcome> = walkdir::WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| {
+            let outcome = curate_file(e.path()).unwrap_or_else(|e| Outcome::Failed(e.to_string()));
+            if let Outcome::Failed(error) = &outcome {
+                warn!("Failed to curate {}: {error}", e.path().display());
+            }
+            FileOutcome {
+                path: e.path().display().to_string(),
+                outcome,
+            }
+        })
+        .collect();
+    let count = |f: fn(&Outcome) -> bool| outcomes.iter().filter(|o| f(&o.outcome)).count();
+    info!(
+        "Curated {} files: {} decompressed, {} empty and {} x1 files deleted, {} failures",
+        outcomes.len(),
+        count(|o| matches!(o, Outcome::Decompressed(_))),
+        count(|o| *o == Outcome::DeletedEmpty),
+        count(|o| *o == Outcome::DeletedX1),
+        count(|o| matches!(o, Outcome::Failed(_))),
+    );
+    outcomes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_curate_dir() {
+        let dir = std::env::temp_dir().join("test_curate_dir");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(b"<Root>gzip</Root>").unwrap();
+        std::fs::write(dir.join("a.gz"), gzip.finish().unwrap()).unwrap();
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("b.xml", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(b"<Root>zip</Root>").unwrap();
+        // A zip archive named .gz.
+        std::fs::write(dir.join("b.gz"), zip.finish().unwrap().into_inner()).unwrap();
+
+        std::fs::write(dir.join("c.xml"), "<Root>plain</Root>").unwrap();
+        std::fs::write(dir.join("d.xml"), "").unwrap();
+        std::fs::write(dir.join("e.x1"), "x1").unwrap();
+        std::fs::write(dir.join("f.gz"), [0x1f, 0x8b, 0, 0]).unwrap();
+
+        let outcomes = curate_dir(&dir)
+            .into_iter()
+            .map(|o| o.outcome)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes[..5],
+            [
+                Outcome::Decompressed(Compression::Gzip),
+                Outcome::Decompressed(Compression::Zip),
+                Outcome::Kept,
+                Outcome::DeletedEmpty,
+                Outcome::DeletedX1,
+            ]
+        );
+        // A truncated gzip file.
+        assert!(matches!(outcomes[5], Outcome::Failed(_)));
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("a"), "<Root>gzip</Root>");
+        assert_eq!(read("b"), "<Root>zip</Root>");
+        assert_eq!(read("c.xml"), "<Root>plain</Root>");
+        assert!(!dir.join("a.gz").exists());
+        assert!(!dir.join("d.xml").exists());
+        assert!(!dir.join("e.x1").exists());
+        assert!(dir.join("f.gz").exists());
+    }
+}
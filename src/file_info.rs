@@ -1,4 +1,5 @@
 use anyhow::{anyhow, Result};
+use chrono::{NaiveDate, NaiveDateTime};
 use result_inspect::ResultInspectErr;
 use std::collections::HashMap;
 use std::str::FromStr;
@@ -57,16 +58,104 @@ impl FileType {
         return Err(anyhow!("Unrecognized file type: {name}"));
     }
     fn is_interesting(self: &Self) -> bool {
-        return FileType::PriceFull == *self || *self == FileType::StoresFull;
+        matches!(
+            self,
+            FileType::PriceFull | FileType::StoresFull | FileType::PromoFull
+        )
+    }
+}
+
+impl FromStr for FileType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "stores" | "storesfull" => Ok(FileType::StoresFull),
+            "pricefull" => Ok(FileType::PriceFull),
+            "promofull" => Ok(FileType::PromoFull),
+            "price" => Ok(FileType::Price),
+            "promo" => Ok(FileType::Promo),
+            _ => Err(anyhow!(
+                "Unknown file type {s}, expected stores, pricefull, promofull, price or promo"
+            )),
+        }
+    }
+}
+
+// Which of the files listed by a website should be downloaded. Empty filters select everything.
+#[derive(Debug, Default, Clone)]
+pub struct FileSelection {
+    pub file_limit: Option<usize>,
+    // Also keep the Price files that were published after the most recent PriceFull of a store.
+    pub price_updates: bool,
+    pub chains: Vec<String>,
+    pub store_ids: Vec<String>,
+    // Replaces the default StoresFull, PriceFull and PromoFull.
+    pub file_types: Vec<FileType>,
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+    // Keep every file instead of only the most recent one, in a directory per day.
+    pub archive: bool,
+}
+
+impl FileSelection {
+    pub fn is_interesting(&self, file_info: &FileInfo) -> bool {
+        let selected_type = match self.file_types.is_empty() {
+            true => file_info.is_interesting(),
+            false => self.file_types.contains(&file_info.file_type),
+        };
+        (selected_type || (self.price_updates && file_info.file_type == FileType::Price))
+            && self.matches_filters(file_info)
+    }
+
+    // Where a file is downloaded: data_raw/<store>/<file>, or archive/<store>/<yyyy-mm-dd>/<file>.
+    pub fn dest(&self, store_name: &str, file_info: &FileInfo) -> String {
+        if !self.archive {
+            return format!("data_raw/{store_name}/{}", file_info.filename);
+        }
+        let day = file_info
+            .datetime()
+            .map_or("unknown_date".to_string(), |d| {
+                d.format("%Y-%m-%d").to_string()
+            });
+        format!("archive/{store_name}/{day}/{}", file_info.filename)
+    }
+
+    fn matches_filters(&self, file_info: &FileInfo) -> bool {
+        if !self.chains.is_empty() && !self.chains.contains(&file_info.chain) {
+            return false;
+        }
+        // Stores files describe all the stores of a chain.
+        if !self.store_ids.is_empty()
+            && file_info.file_type != FileType::StoresFull
+            && !self
+                .store_ids
+                .iter()
+                .any(|id| id.trim_start_matches('0') == file_info.store)
+        {
+            return false;
+        }
+        if self.since.is_none() && self.until.is_none() {
+            return true;
+        }
+        let Some(date) = file_info.datetime().map(|d| d.date()) else {
+            return false;
+        };
+        self.includes_day(date)
+    }
+
+    // Whether the day is within --since and --until, e.g. to walk the days of an archive.
+    pub fn includes_day(&self, day: NaiveDate) -> bool {
+        self.since.is_none_or(|since| since <= day) && self.until.is_none_or(|until| day <= until)
     }
 }
 
 #[derive(Debug)]
 pub struct FileInfo {
-    file_type: FileType,
-    chain: String,
-    store: String,
-    date: String,
+    pub file_type: FileType,
+    pub chain: String,
+    pub store: String,
+    pub date: String,
     pub filename: String,
     pub source: String,
     pub cookie: String, // needed only when using a different cookie per file.
@@ -90,6 +179,11 @@ impl FileInfo {
         }
     }
 
+    // Dates are written as yyyymmddHHMM, sometimes followed by seconds.
+    pub fn datetime(&self) -> Option<NaiveDateTime> {
+        NaiveDateTime::parse_from_str(self.date.get(..12)?, "%Y%m%d%H%M").ok()
+    }
+
     pub fn key(self: &Self) -> (FileType, String, String) {
         return (self.file_type, self.chain.clone(), self.store.clone());
     }
@@ -97,7 +191,7 @@ impl FileInfo {
     #[allow(unstable_name_collisions)]
     pub fn from_str_iter(
         vals: impl Iterator<Item = String>,
-        file_limit: Option<usize>,
+        selection: &FileSelection,
     ) -> std::vec::IntoIter<FileInfo> {
         FileInfo::keep_most_recents(
             vals.filter_map(|link| {
@@ -105,20 +199,33 @@ impl FileInfo {
                     .inspect_err(|e| error!("Error parsing filename: {e}"))
                     .ok()
             })
-            .filter(|fi| fi.is_interesting())
+            .filter(|fi| selection.is_interesting(fi))
             .collect(),
-            file_limit,
+            selection,
         )
         .into_iter()
     }
 
-    pub fn keep_most_recents(data: Vec<FileInfo>, file_limit: Option<usize>) -> Vec<FileInfo> {
+    pub fn keep_most_recents(data: Vec<FileInfo>, selection: &FileSelection) -> Vec<FileInfo> {
+        if selection.archive {
+            let mut data = data;
+            data.sort_by(|a, b| a.filename.cmp(&b.filename));
+            if let Some(i) = selection.file_limit {
+                data.truncate(i);
+            }
+            return data;
+        }
         let mut non_stores: HashMap<(FileType, String, String), FileInfo> = HashMap::new();
         let mut stores: HashMap<(FileType, String, String), FileInfo> = HashMap::new();
+        let mut price_updates: Vec<FileInfo> = Vec::new();
 
         for file_info in data {
             let map = match file_info.file_type {
                 FileType::StoresFull => &mut stores,
+                FileType::Price if selection.price_updates => {
+                    price_updates.push(file_info);
+                    continue;
+                }
                 _ => &mut non_stores,
             };
             if !map.contains_key(&file_info.key())
@@ -128,12 +235,27 @@ impl FileInfo {
             }
         }
 
+        // Price updates are only useful on top of the PriceFull they follow.
+        let price_updates = price_updates.into_iter().filter(|file_info| {
+            non_stores
+                .get(&(
+                    FileType::PriceFull,
+                    file_info.chain.clone(),
+                    file_info.store.clone(),
+                ))
+                .is_some_and(|base| base.date < file_info.date)
+        });
+        let price_updates = price_updates.collect::<Vec<FileInfo>>();
+
         let mut recents: Vec<FileInfo> = stores.into_values().collect::<Vec<FileInfo>>();
-        let recents_non_stores: Vec<FileInfo> = match file_limit {
-            Some(i) => non_stores.into_values().take(i).collect::<Vec<FileInfo>>(),
-            None => non_stores.into_values().collect::<Vec<FileInfo>>(),
-        };
+        // Sorted so that the files kept by file_limit don't change from one run to another.
+        let mut recents_non_stores = non_stores.into_values().collect::<Vec<FileInfo>>();
+        recents_non_stores.sort_by(|a, b| a.filename.cmp(&b.filename));
+        if let Some(i) = selection.file_limit {
+            recents_non_stores.truncate(i);
+        }
         recents.extend(recents_non_stores);
+        recents.extend(price_updates);
         recents
     }
 }
@@ -175,10 +297,10 @@ impl FromStr for FileInfo {
             });
         }
 
+        // Files that were already unzipped may have no extension at all.
         let mut parts: Vec<String> = filename
             .strip_prefix(prefix)
-            .and_then(|s| s.split_once("."))
-            .map(|s| s.0)
+            .map(|s| s.split_once(".").map_or(s, |s| s.0))
             .map(|s| {
                 s.split("-")
                     .map(|s| s.trim_start_matches('0').to_string())
@@ -224,4 +346,109 @@ mod tests {
         let f: FileInfo = "Price7290058179875-040-202210261508.gz".parse().unwrap();
         assert!(!f.is_interesting());
     }
+
+    #[test]
+    fn test_keep_price_updates_after_price_full() {
+        let selection = FileSelection {
+            price_updates: true,
+            ..Default::default()
+        };
+        let files = [
+            "PriceFull7290058179875-040-202210260300.gz",
+            "Price7290058179875-040-202210260200.gz",
+            "Price7290058179875-040-202210261508.gz",
+            "Price7290058179875-041-202210261508.gz",
+        ]
+        .into_iter()
+        .map(str::to_string);
+
+        let mut kept = FileInfo::from_str_iter(files.clone(), &selection)
+            .map(|f| f.filename)
+            .collect::<Vec<String>>();
+        kept.sort();
+        assert_eq!(
+            kept,
+            vec![
+                "Price7290058179875-040-202210261508.gz",
+                "PriceFull7290058179875-040-202210260300.gz"
+            ]
+        );
+
+        let kept = FileInfo::from_str_iter(files, &FileSelection::default()).count();
+        assert_eq!(kept, 1);
+    }
+
+    #[test]
+    fn test_filter_files() {
+        let selection = FileSelection {
+            chains: vec!["7290027600007".to_string()],
+            store_ids: vec!["001".to_string(), "2".to_string()],
+            file_types: vec!["stores".parse().unwrap(), "pricefull".parse().unwrap()],
+            since: NaiveDate::from_ymd_opt(2023, 5, 1),
+            until: NaiveDate::from_ymd_opt(2023, 5, 31),
+            ..Default::default()
+        };
+        let files = [
+            "Stores7290027600007-000-202305070300.gz",
+            "PriceFull7290027600007-001-202305070300.gz",
+            "PriceFull7290027600007-002-202305070300.gz",
+            "PriceFull7290027600007-003-202305070300.gz",
+            "PriceFull7290027600007-001-202306070300.gz",
+            "PromoFull7290027600007-001-202305070300.gz",
+            "PriceFull7290058179875-001-202305070300.gz",
+        ];
+        let kept = files
+            .into_iter()
+            .filter(|f| selection.is_interesting(&f.parse().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kept,
+            vec![
+                "Stores7290027600007-000-202305070300.gz",
+                "PriceFull7290027600007-001-202305070300.gz",
+                "PriceFull7290027600007-002-202305070300.gz",
+            ]
+        );
+        assert!("promos".parse::<FileType>().is_err());
+    }
+
+    #[test]
+    fn test_archive() {
+        let selection = FileSelection {
+            archive: true,
+            ..Default::default()
+        };
+        let files = [
+            "PriceFull7290027600007-001-202305070300.gz",
+            "PriceFull7290027600007-001-202305080300.gz",
+            "Stores7290027600007-000-202305070300.gz",
+        ]
+        .into_iter()
+        .map(str::to_string);
+        let dests = FileInfo::from_str_iter(files, &selection)
+            .map(|f| selection.dest("shufersal", &f))
+            .collect::<Vec<String>>();
+        assert_eq!(
+            dests,
+            vec![
+                "archive/shufersal/2023-05-07/PriceFull7290027600007-001-202305070300.gz",
+                "archive/shufersal/2023-05-08/PriceFull7290027600007-001-202305080300.gz",
+                "archive/shufersal/2023-05-07/Stores7290027600007-000-202305070300.gz",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_unzipped_filename() {
+        let f: FileInfo = "data_raw/shufersal/PriceFull7290027600007-001-202305070300"
+            .parse()
+            .unwrap();
+        assert_eq!(f.chain, "7290027600007");
+        assert_eq!(f.store, "1");
+        assert_eq!(f.date, "202305070300");
+        assert_eq!(
+            f.datetime(),
+            NaiveDateTime::parse_from_str("2023-05-07 03:00", "%Y-%m-%d %H:%M").ok()
+        );
+    }
 }
@@ -0,0 +1,146 @@
+use anyhow::{Context, Result};
+
+use crate::hebrew;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Street types written before the name of the street, e.g. "רח' הרצל 12".
+const STREET_PREFIXES: &[&str] = &["רח", "רחוב", "שד", "שדרות", "שדרת", "סמ", "סמטת"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+// The chains write the same names with different punctuation, e.g. "קרית-גת", "קריית גת".
+pub fn normalize_name(name: &str) -> String {
+    let name: String = hebrew::unify_quotes(&hebrew::remove_niqqud(name))
+        .chars()
+        .map(|c| match c {
+            '\u{05BE}' | '-' | '–' | '.' | ',' | '(' | ')' | '/' => ' ',
+            '\'' | '"' => '\0',
+            c => c,
+        })
+        .filter(|c| *c != '\0')
+        .collect();
+    name.replace("קריית", "קרית")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// The street of an address, without its type, house number or city: "רח' הרצל 12, חולון" -> "הרצל".
+pub fn normalize_street(address: &str) -> String {
+    let street = address.split(',').next().unwrap_or("");
+    let tokens = normalize_name(street)
+        .split(' ')
+        .filter(|t| !t.chars().any(|c| c.is_ascii_digit()))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    match tokens.first() {
+        Some(first) if tokens.len() > 1 && STREET_PREFIXES.contains(&first.as_str()) => {
+            tokens[1..].join(" ")
+        }
+        _ => tokens.join(" "),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct GazetteerRow {
+    city: String,
+    #[serde(default)]
+    street: String,
+    latitude: f64,
+    longitude: f64,
+}
+
+// Coordinates of cities and streets, read from a CSV file with the columns
+// city,street,latitude,longitude. Rows without a street give the center of the city.
+#[derive(Debug, Default)]
+pub struct Gazetteer {
+    cities: HashMap<String, Location>,
+    streets: HashMap<(String, String), Location>,
+}
+
+impl Gazetteer {
+    pub fn load(path: &str) -> Result<Gazetteer> {
+        let mut reader =
+            csv::Reader::from_path(path).with_context(|| format!("Cannot read {path}"))?;
+        let mut gazetteer = Gazetteer::default();
+        for row in reader.deserialize() {
+            let row: GazetteerRow = row.with_context(|| format!("Invalid row in {path}"))?;
+            let location = Location {
+                latitude: row.latitude,
+                longitude: row.longitude,
+            };
+            let city = normalize_name(&row.city);
+            match normalize_street(&row.street).as_str() {
+                "" => gazetteer.cities.insert(city, location),
+                street => gazetteer
+                    .streets
+                    .insert((city, street.to_string()), location),
+            };
+        }
+        Ok(gazetteer)
+    }
+
+    // The location of the street when it is known, or else of the city.
+    pub fn locate(&self, city: &str, address: &str) -> Option<Location> {
+        let city = normalize_name(city);
+        self.streets
+            .get(&(city.clone(), normalize_street(address)))
+            .or_else(|| self.cities.get(&city))
+            .copied()
+    }
+}
+
+// The great-circle distance, in kilometers.
+pub fn distance_km(a: Location, b: Location) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+    let (lat_a, lat_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.longitude - a.longitude).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        assert_eq!(normalize_name("קריית-גת"), "קרית גת");
+        assert_eq!(normalize_name("ת\"א"), "תא");
+        assert_eq!(normalize_street("רח' הרצל 12, חולון"), "הרצל");
+        assert_eq!(normalize_street("שד. רוטשילד 5-7"), "רוטשילד");
+        assert_eq!(normalize_street("דרך מנחם בגין 132"), "דרך מנחם בגין");
+
+        let gazetteer = Gazetteer::load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/test_data/gazetteer.csv"
+        ))
+        .unwrap();
+        let herzl = gazetteer.locate("חולון", "הרצל 40").unwrap();
+        assert_eq!(herzl.latitude, 32.0158);
+        let holon = gazetteer.locate("חולון", "רחוב לא ידוע 3").unwrap();
+        assert_eq!(holon.latitude, 32.0114);
+        let kiryat_gat = gazetteer.locate("קרית-גת", "").unwrap();
+        assert_eq!(kiryat_gat.longitude, 34.7642);
+        assert_eq!(gazetteer.locate("אילת", "התמרים 1"), None);
+
+        // Tel Aviv to Jerusalem.
+        let distance = distance_km(
+            Location {
+                latitude: 32.0853,
+                longitude: 34.7818,
+            },
+            Location {
+                latitude: 31.7683,
+                longitude: 35.2137,
+            },
+        );
+        assert!((distance - 54.0).abs() < 1.0, "{distance}");
+    }
+}
@@ -0,0 +1,261 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+
+use crate::units::{parse_unit, Quantity, Unit};
+
+// Words that only advertise the item, e.g. "*מבצע* קינדר בואנו".
+const PROMOTIONAL: &[&str] = &[
+    "מבצע",
+    "במבצע",
+    "חדש",
+    "חדשה",
+    "בלעדי",
+    "מוזל",
+    "מחיר מיוחד",
+    "מהדורה מוגבלת",
+    "1+1",
+    "2+1",
+];
+
+lazy_static! {
+    // Abbreviations of units, as written after amounts.
+    static ref ABBREVIATIONS: HashMap<&'static str, &'static str> = HashMap::from([
+        ("ג'", "גרם"),
+        ("גר'", "גרם"),
+        ("גר", "גרם"),
+        ("ק\"ג", "קילוגרם"),
+        ("קג", "קילוגרם"),
+        ("מ\"ל", "מיליליטר"),
+        ("מל", "מיליליטר"),
+        ("ל'", "ליטר"),
+        ("יח'", "יחידות"),
+        ("יח", "יחידות"),
+    ]);
+
+    // Brands, with the ways chains spell them.
+    static ref BRANDS: Vec<(&'static str, Vec<&'static str>)> = vec![
+        ("תנובה", vec!["תנובה"]),
+        ("שטראוס", vec!["שטראוס"]),
+        ("עלית", vec!["עלית"]),
+        ("אסם", vec!["אסם", "אוסם"]),
+        ("טרה", vec!["טרה"]),
+        ("יטבתה", vec!["יטבתה"]),
+        ("תלמה", vec!["תלמה"]),
+        ("ויסוצקי", vec!["ויסוצקי"]),
+        ("סוגת", vec!["סוגת"]),
+        ("קנור", vec!["קנור"]),
+        ("ריטר", vec!["ריטר", "ריטר ספורט"]),
+        ("דנונה", vec!["דנונה"]),
+        ("היינץ", vec!["היינץ"]),
+        ("קוקה קולה", vec!["קוקה קולה", "coca cola"]),
+        ("פריגת", vec!["פריגת"]),
+        ("פלמוליב", vec!["פלמוליב"]),
+        ("סנו", vec!["סנו"]),
+        ("קינדר", vec!["קינדר"]),
+        ("נסטלה", vec!["נסטלה", "nestle"]),
+        ("זוגלובק", vec!["זוגלובק"]),
+        ("תפוגן", vec!["תפוגן"]),
+        ("קולגייט", vec!["קולגייט", "colgate"]),
+        ("דורות", vec!["דורות"]),
+        ("מימונס", vec!["מימונס"]),
+        ("פרי ניר", vec!["פרי ניר"]),
+        ("מאסט", vec!["מאסט"]),
+    ];
+}
+
+// An item name split into what identifies the product.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemName {
+    // The normalized name, without the quantity and the promotional words.
+    pub name: String,
+    pub quantity: Option<Quantity>,
+    pub brand: Option<&'static str>,
+}
+
+pub fn remove_niqqud(s: &str) -> String {
+    s.chars()
+        .filter(|c| !('\u{0591}'..='\u{05C7}').contains(c) || *c == '\u{05BE}')
+        .collect()
+}
+
+// Hebrew and typographic geresh and gershayim become ASCII quotes: "ק״ג" -> "ק\"ג".
+pub fn unify_quotes(s: &str) -> String {
+    s.replace("''", "\"")
+        .chars()
+        .map(|c| match c {
+            '\u{05F3}' | '`' | '’' | '‘' => '\'',
+            '\u{05F4}' | '“' | '”' => '"',
+            c => c,
+        })
+        .collect()
+}
+
+fn is_hebrew(c: char) -> bool {
+    ('\u{05D0}'..='\u{05EA}').contains(&c)
+}
+
+// Splits "100ג'" and "ורוד1" into words, and hyphens and stars into spaces, but not in "7-12".
+fn separate_words(s: &str) -> String {
+    let chars = s.chars().collect::<Vec<_>>();
+    let mut out = String::with_capacity(s.len());
+    for (i, &c) in chars.iter().enumerate() {
+        let prev = i.checked_sub(1).map(|i| chars[i]);
+        let next = chars.get(i + 1).copied();
+        match c {
+            '-' | '–' | '\u{05BE}'
+                if !(prev.is_some_and(|p| p.is_ascii_digit())
+                    && next.is_some_and(|n| n.is_ascii_digit())) =>
+            {
+                out.push(' ')
+            }
+            '*' | '!' | ',' | '(' | ')' => out.push(' '),
+            c => {
+                if prev.is_some_and(|p| {
+                    (p.is_ascii_digit() && is_hebrew(c)) || (is_hebrew(p) && c.is_ascii_digit())
+                }) {
+                    out.push(' ');
+                }
+                out.push(c);
+            }
+        }
+    }
+    out
+}
+
+fn words(s: &str) -> Vec<String> {
+    s.split_whitespace()
+        .map(|w| w.trim_matches(|c| c == '.' || c == ':').to_string())
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+fn remove_phrases(words: &mut Vec<String>, phrases: &[&str]) {
+    for phrase in phrases {
+        let phrase = phrase.split(' ').collect::<Vec<_>>();
+        while let Some(start) = words
+            .windows(phrase.len())
+            .position(|window| window.iter().zip(&phrase).all(|(a, b)| a == b))
+        {
+            words.drain(start..start + phrase.len());
+        }
+    }
+}
+
+// Niqqud, quotes, unit abbreviations and promotional words normalized:
+// "*מבצע* שמנת חמוצה 15% 200מ״ל" -> "שמנת חמוצה 15% 200 מיליליטר".
+pub fn normalize(name: &str) -> String {
+    let mut words = words(&separate_words(&unify_quotes(&remove_niqqud(name))));
+    remove_phrases(&mut words, PROMOTIONAL);
+    words
+        .iter()
+        .map(|w| ABBREVIATIONS.get(w.as_str()).copied().unwrap_or(w))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// An amount, or the product of a multipack: "330X6" -> 1980.
+fn parse_amount(s: &str) -> Option<f64> {
+    s.split(['x', 'X', '×'])
+        .map(|n| n.parse::<f64>().ok().filter(|n| *n > 0.0))
+        .product()
+}
+
+// The quantity written in a name, preferring a weight or a volume to a number of units.
+// Amounts "per" something, as in "19 קלוריות ב 100 מיליליטר", are ignored. Returns the
+// words around it.
+fn split_quantity(words: &[&str]) -> (Vec<String>, Option<Quantity>) {
+    let mut found: Option<(usize, Quantity)> = None;
+    for i in 0..words.len().saturating_sub(1) {
+        if i > 0 && matches!(words[i - 1], "ב" | "ל" | "לכל") {
+            continue;
+        }
+        let Some(amount) = parse_amount(words[i]) else {
+            continue;
+        };
+        let Some(unit) = parse_unit(words[i + 1]) else {
+            continue;
+        };
+        let quantity = Quantity {
+            amount: amount * unit.amount,
+            unit: unit.unit,
+        };
+        match found {
+            Some((_, q)) if q.unit != Unit::Unit || quantity.unit == Unit::Unit => {}
+            _ => found = Some((i, quantity)),
+        }
+    }
+    let mut used = found.map_or(Vec::new(), |(at, _)| vec![at, at + 1]);
+    // A count after a weight or a volume is a multipack, like "330X6": "500 מ"ל 12 יחידות" -> 6000 מ"ל.
+    if let Some((at, quantity)) = found.as_mut().filter(|(_, q)| q.unit != Unit::Unit) {
+        let count = words.get(*at + 2).and_then(|w| parse_amount(w));
+        let unit = words.get(*at + 3).and_then(|w| parse_unit(w));
+        if let (Some(count), Some(unit)) = (count, unit.filter(|u| u.unit == Unit::Unit)) {
+            quantity.amount *= count * unit.amount;
+            used.extend([*at + 2, *at + 3]);
+        }
+    }
+    let rest = words
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !used.contains(i))
+        .map(|(_, w)| w.to_string())
+        .collect();
+    (rest, found.map(|(_, q)| q))
+}
+
+pub fn detect_brand(name: &str) -> Option<&'static str> {
+    let name_words = words(&separate_words(&unify_quotes(name).to_lowercase()));
+    BRANDS.iter().find_map(|(brand, spellings)| {
+        spellings
+            .iter()
+            .any(|spelling| {
+                let spelling = words(&separate_words(spelling));
+                name_words
+                    .windows(spelling.len())
+                    .any(|window| window.iter().zip(&spelling).all(|(a, b)| a == b))
+            })
+            .then_some(*brand)
+    })
+}
+
+pub fn parse_item_name(name: &str) -> ItemName {
+    let normalized = normalize(name);
+    let (rest, quantity) = split_quantity(&normalized.split(' ').collect::<Vec<_>>());
+    ItemName {
+        name: rest.join(" "),
+        quantity,
+        brand: detect_brand(&normalized),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each line of the corpus is a name from the chains files, the expected name, quantity and
+    // brand, separated by tabs.
+    #[test]
+    fn test_parse_item_name() {
+        let corpus = std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/test_data/item_names.tsv"
+        ))
+        .unwrap();
+        for line in corpus.lines().filter(|line| !line.is_empty()) {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let [raw, name, quantity, brand] = fields[..] else {
+                panic!("Invalid line {line}");
+            };
+            let parsed = parse_item_name(raw);
+            assert_eq!(parsed.name, name, "{raw}");
+            let quantity = match quantity {
+                "" => None,
+                quantity => parse_unit(quantity),
+            };
+            assert_eq!(parsed.quantity, quantity, "{raw}");
+            assert_eq!(parsed.brand.unwrap_or(""), brand, "{raw}");
+        }
+        assert_eq!(remove_niqqud("שָׁלוֹם"), "שלום");
+        assert_eq!(unify_quotes("ק״ג יח׳"), "ק\"ג יח'");
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use tracing::info;
+
+use crate::models::{Barcode, ChainId, Prices, StoreId};
+use crate::price::Price;
+
+// Unlike data.sqlite, the history database is never deleted, and grows with each run.
+fn connection() -> Result<Connection> {
+    let path = "history.sqlite";
+    let connection = rusqlite::Connection::open(path)?;
+    create_tables(&connection)?;
+    Ok(connection)
+}
+
+fn create_tables(connection: &Connection) -> Result<()> {
+    connection.execute(
+        "CREATE TABLE IF NOT EXISTS PriceHistory (
+                        ChainId int NOT NULL,
+                        StoreId int NOT NULL,
+                        ItemCode int NOT NULL,
+                        ItemPrice INTEGER,
+                        UnitOfMeasurePrice INTEGER,
+                        PriceUpdateDate TEXT,
+                        FileDate TEXT NOT NULL,
+                        PRIMARY KEY(ChainId, StoreId, ItemCode, FileDate))",
+        (),
+    )?;
+    connection.execute(
+        "CREATE INDEX IF NOT EXISTS PriceHistoryItemCode ON PriceHistory (ItemCode)",
+        (),
+    )?;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct PriceChange {
+    pub chain_id: ChainId,
+    pub store_id: StoreId,
+    pub price: Option<Price>,
+    pub file_date: String,
+}
+
+// Only the prices that differ from the last known price of the item in the store are saved,
+// so that each row of the table is a price change.
+pub fn save_price_history(prices: &[Prices]) -> Result<()> {
+    let changes = save_price_changes(&mut connection()?, prices)?;
+    info!("Saved {changes} price changes to history.sqlite");
+    Ok(())
+}
+
+fn save_price_changes(connection: &mut Connection, prices: &[Prices]) -> Result<usize> {
+    info!("Reading latest prices from history.sqlite");
+    let mut latest_prices: HashMap<(ChainId, StoreId, Barcode), Option<Price>> = HashMap::new();
+    {
+        // SQLite returns the values of the row holding the maximum for bare columns.
+        let mut statement = connection.prepare(
+            "SELECT ChainId, StoreId, ItemCode, ItemPrice, max(FileDate)
+            FROM PriceHistory
+            GROUP BY ChainId, StoreId, ItemCode",
+        )?;
+        let mut rows = statement.query(())?;
+        while let Some(row) = rows.next()? {
+            latest_prices.insert((row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?);
+        }
+    }
+    info!("Found {} known prices", latest_prices.len());
+
+    let now = chrono::Local::now().naive_local();
+    let mut changes = 0;
+    let transaction = connection.transaction()?;
+    {
+        let tx = &transaction;
+        let mut statement = tx.prepare(
+            "INSERT OR REPLACE INTO PriceHistory (
+                ChainId,
+                StoreId,
+                ItemCode,
+                ItemPrice,
+                UnitOfMeasurePrice,
+                PriceUpdateDate,
+                FileDate) VALUES (?1,?2,?3,?4,?5,?6,?7)",
+        )?;
+        for price in prices {
+            for item in &price.items {
+                let key = (price.chain_id, price.store_id, item.item_code);
+                if latest_prices.get(&key) == Some(&item.item_price) {
+                    continue;
+                }
+                // Items of update files are dated by the update they were read from.
+                let file_date = item
+                    .file_date
+                    .or(price.file_date)
+                    .unwrap_or(now)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string();
+                statement
+                    .execute(params![
+                        price.chain_id,
+                        price.store_id,
+                        item.item_code,
+                        item.item_price,
+                        item.unit_of_measure_price,
+                        item.price_update_date,
+                        file_date,
+                    ])
+                    .with_context(|| format!("With key = {:?}", key))?;
+                changes += 1;
+            }
+        }
+    }
+    transaction.commit()?;
+    Ok(changes)
+}
+
+pub fn get_price_changes(item_code: Barcode) -> Result<Vec<PriceChange>> {
+    let connection = connection()?;
+    let mut statement = connection.prepare(
+        "SELECT ChainId, StoreId, ItemPrice, FileDate
+        FROM PriceHistory
+        WHERE ItemCode = ?1
+        ORDER BY FileDate, ChainId, StoreId",
+    )?;
+    let mut rows = statement.query(params![item_code])?;
+    let mut changes = Vec::new();
+    while let Some(row) = rows.next()? {
+        changes.push(PriceChange {
+            chain_id: row.get(0)?,
+            store_id: row.get(1)?,
+            price: row.get(2)?,
+            file_date: row.get(3)?,
+        });
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Item;
+
+    fn prices(file_date: &str, items: &[(Barcode, &str)]) -> Prices {
+        Prices {
+            chain_id: 7290027600007,
+            subchain_id: 1,
+            store_id: 1,
+            verification_num: 0,
+            items: items
+                .iter()
+                .map(|(item_code, price)| Item {
+                    item_code: *item_code,
+                    item_price: Some(price.parse().unwrap()),
+                    ..Default::default()
+                })
+                .collect(),
+            file_date: chrono::NaiveDateTime::parse_from_str(file_date, "%Y-%m-%d %H:%M:%S").ok(),
+        }
+    }
+
+    fn history(connection: &Connection) -> Vec<(Barcode, Option<Price>, String)> {
+        let mut statement = connection
+            .prepare("SELECT ItemCode, ItemPrice, FileDate FROM PriceHistory ORDER BY FileDate, ItemCode")
+            .unwrap();
+        statement
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_save_price_changes() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        create_tables(&connection).unwrap();
+        let first = prices("2023-06-10 08:00:00", &[(1, "5.90"), (2, "12.00")]);
+        assert_eq!(save_price_changes(&mut connection, &[first]).unwrap(), 2);
+
+        // Only the item whose price changed gets a row, dated by the update it came from.
+        let mut second = prices("2023-06-11 08:00:00", &[(1, "5.90"), (2, "12.00")]);
+        second.items[1].item_price = Some("10.90".parse().unwrap());
+        second.items[1].file_date =
+            chrono::NaiveDateTime::parse_from_str("2023-06-11 14:00:00", "%Y-%m-%d %H:%M:%S").ok();
+        assert_eq!(save_price_changes(&mut connection, &[second]).unwrap(), 1);
+        assert_eq!(
+            history(&connection),
+            vec![
+                (
+                    1,
+                    Some("5.90".parse().unwrap()),
+                    "2023-06-10 08:00:00".to_string()
+                ),
+                (
+                    2,
+                    Some("12.00".parse().unwrap()),
+                    "2023-06-10 08:00:00".to_string()
+                ),
+                (
+                    2,
+                    Some("10.90".parse().unwrap()),
+                    "2023-06-11 14:00:00".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use anyhow::{anyhow, Result};
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::manifest::sha256;
+
+// The websites can be replaced by a local stand-in server, which either records the responses
+// of the real websites (acting as a proxy), or replays responses recorded earlier.
+// Requests are sent to <server>/<scheme>/<host>/<path>, see `url`.
+lazy_static! {
+    static ref STAND_IN: RwLock<Option<String>> = RwLock::new(None);
+}
+
+// A recorded response. The body is stored next to it, in `body_file`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fixture {
+    pub method: String,
+    pub url: String,
+    // Matches any body when missing.
+    #[serde(default)]
+    pub request_body: Option<String>,
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    pub body_file: String,
+    // Replayed only for the first matching request, e.g. to expire a session once.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub once: bool,
+}
+
+// The url to request instead of `url`, which goes through the stand-in server when there is one.
+pub fn url(url: &str) -> String {
+    let stand_in = STAND_IN.read().unwrap();
+    let Some(base) = stand_in.as_ref() else {
+        return url.to_string();
+    };
+    match url.split_once("://") {
+        Some((scheme, rest)) => format!("{base}/{scheme}/{rest}"),
+        None => url.to_string(),
+    }
+}
+
+struct StandIn {
+    dir: PathBuf,
+    record: bool,
+    client: reqwest::Client,
+    // The body files of the fixtures replayed once that were already replayed.
+    replayed_once: Mutex<HashSet<PathBuf>>,
+}
+
+// The url requested from the stand-in server, e.g. /https/example.com/a?b -> https://example.com/a?b
+fn original_url(uri: &Uri) -> Option<String> {
+    let (scheme, rest) = uri.path().strip_prefix('/')?.split_once('/')?;
+    let query = uri.query().map_or(String::new(), |q| format!("?{q}"));
+    Some(format!("{scheme}://{rest}{query}"))
+}
+
+// Form parameters may be sent in any order.
+fn canonical_body(headers: &HeaderMap, body: &[u8]) -> String {
+    let body = String::from_utf8_lossy(body);
+    let is_form = headers
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    match is_form {
+        true => body.split('&').sorted().join("&"),
+        false => body.to_string(),
+    }
+}
+
+fn host_dir(dir: &Path, url: &str) -> PathBuf {
+    let host = crate::rate_limit::host(url).unwrap_or("unknown".to_string());
+    dir.join(host)
+}
+
+pub fn load_fixtures(dir: &Path, url: &str) -> Vec<Fixture> {
+    let Ok(entries) = std::fs::read_dir(host_dir(dir, url)) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|e| e == "json"))
+        .sorted()
+        .filter_map(|p| {
+            let fixture = std::fs::read_to_string(&p)
+                .map_err(anyhow::Error::from)
+                .and_then(|s| Ok(serde_json::from_str::<Fixture>(&s)?));
+            if let Err(e) = &fixture {
+                warn!("Invalid fixture {}: {e}", p.display());
+            }
+            fixture.ok()
+        })
+        .collect()
+}
+
+fn header_map(headers: &[(String, String)]) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        map.append(
+            reqwest::header::HeaderName::from_bytes(name.as_bytes())?,
+            value.parse()?,
+        );
+    }
+    Ok(map)
+}
+
+fn replay(stand_in: &StandIn, method: &Method, url: &str, body: &str) -> Result<Response> {
+    let dir = host_dir(&stand_in.dir, url);
+    let mut replayed_once = stand_in.replayed_once.lock().unwrap();
+    let fixture = load_fixtures(&stand_in.dir, url)
+        .into_iter()
+        .find(|f| {
+            f.method == method.as_str()
+                && f.url == url
+                && f.request_body.as_ref().is_none_or(|b| b == body)
+                && !(f.once && replayed_once.contains(&dir.join(&f.body_file)))
+        })
+        .ok_or(anyhow!("No fixture for {method} {url}"))?;
+    if fixture.once {
+        replayed_once.insert(dir.join(&fixture.body_file));
+    }
+    let content = std::fs::read(dir.join(&fixture.body_file))?;
+    let headers = header_map(&fixture.headers)?;
+    Ok((StatusCode::from_u16(fixture.status)?, headers, content).into_response())
+}
+
+async fn record(
+    stand_in: &StandIn,
+    method: Method,
+    url: &str,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Result<Response> {
+    let mut request = stand_in
+        .client
+        .request(method.clone(), url)
+        .body(body.clone());
+    for name in [reqwest::header::COOKIE, reqwest::header::CONTENT_TYPE] {
+        if let Some(value) = headers.get(&name) {
+            request = request.header(name, value);
+        }
+    }
+    let response = request.send().await?;
+    let status = response.status();
+    // Cookies are set for the stand-in server, and redirections go through it.
+    let response_headers = response
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| {
+            let value = value.to_str().ok()?;
+            match name.as_str() {
+                "set-cookie" => Some((
+                    name.to_string(),
+                    value
+                        .split(';')
+                        .filter(|p| !p.trim().to_lowercase().starts_with("domain="))
+                        .join(";"),
+                )),
+                "location" => Some((
+                    name.to_string(),
+                    value
+                        .split_once("://")
+                        .map_or(value.to_string(), |(scheme, rest)| {
+                            format!("/{scheme}/{rest}")
+                        }),
+                )),
+                "content-type" => Some((name.to_string(), value.to_string())),
+                _ => None,
+            }
+        })
+        .collect::<Vec<_>>();
+    let content = response.bytes().await?;
+
+    let request_body = canonical_body(headers, &body);
+    let name = format!(
+        "{}_{}",
+        method.as_str().to_lowercase(),
+        &sha256(format!("{method} {url} {request_body}").as_bytes())[..16]
+    );
+    let fixture = Fixture {
+        method: method.to_string(),
+        url: url.to_string(),
+        request_body: (!request_body.is_empty()).then_some(request_body),
+        status: status.as_u16(),
+        headers: response_headers.clone(),
+        body_file: format!("{name}.body"),
+        once: false,
+    };
+    let dir = host_dir(&stand_in.dir, url);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join(&fixture.body_file), &content)?;
+    std::fs::write(
+        dir.join(format!("{name}.json")),
+        serde_json::to_string_pretty(&fixture)?,
+    )?;
+    debug!("Recorded {method} {url}");
+
+    Ok((status, header_map(&response_headers)?, content).into_response())
+}
+
+async fn handle(
+    State(stand_in): State<Arc<StandIn>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some(url) = original_url(&uri) else {
+        return (StatusCode::BAD_REQUEST, format!("Invalid path {uri}")).into_response();
+    };
+    let result = match stand_in.record {
+        true => record(&stand_in, method, &url, &headers, body).await,
+        false => replay(&stand_in, &method, &url, &canonical_body(&headers, &body)),
+    };
+    result.unwrap_or_else(|e| {
+        warn!("Stand-in server: {e}");
+        (StatusCode::NOT_FOUND, e.to_string()).into_response()
+    })
+}
+
+// Starts the stand-in server, and sends all the requests to it.
+pub fn start(
+    listener: std::net::TcpListener,
+    dir: &Path,
+    record: bool,
+) -> Result<impl std::future::Future<Output = ()>> {
+    let stand_in = Arc::new(StandIn {
+        dir: dir.to_path_buf(),
+        record,
+        client: reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?,
+        replayed_once: Mutex::new(HashSet::new()),
+    });
+    let app = Router::new().fallback(handle).with_state(stand_in);
+    let base = format!("http://{}", listener.local_addr()?);
+    info!(
+        "{} {} through {base}",
+        if record { "Recording" } else { "Replaying" },
+        dir.display()
+    );
+    *STAND_IN.write().unwrap() = Some(base);
+    Ok(async move {
+        let result = match axum::Server::from_tcp(listener) {
+            Ok(server) => server.serve(app.into_make_service()).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Stand-in server stopped: {e}");
+        }
+    })
+}
+
+#[cfg(test)]
+lazy_static! {
+    static ref REPLAY_FOR_TESTS: () = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test_data/http_fixtures");
+        let server = start(listener, &dir, false).unwrap();
+        // The server outlives the runtimes of the tests.
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(server);
+        });
+    };
+}
+
+// Replays the fixtures of test_data/http_fixtures for the rest of the tests.
+#[cfg(test)]
+pub fn replay_for_tests() {
+    lazy_static::initialize(&REPLAY_FOR_TESTS);
+}
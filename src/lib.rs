@@ -1,4 +1,10 @@
+pub mod cities;
+pub mod geocoding;
+pub mod hebrew;
 pub mod models;
 pub mod nutrition;
 pub mod online_store_data;
+pub mod price;
+pub mod rate_limit;
 pub mod reqwest_utils;
+pub mod units;
@@ -1,70 +1,222 @@
 mod counter;
+mod curation;
 mod file_info;
+mod history;
+mod http_fixture;
+mod manifest;
 mod models;
 mod parallel_download;
+mod parse_report;
+mod product_matching;
+mod published_prices;
 mod store;
 mod store_data_download;
 mod xml_to_standard;
+use crate::counter::{ChainWeights, DataCounter, WeightedCounter};
+use crate::file_info::{FileInfo, FileSelection, FileType};
+use crate::models::ItemInfo;
 use crate::models::{ItemKey, ItemPrice};
-use crate::{counter::DataCounter, models::ItemInfo};
+use crate::parse_report::ParseReport;
+use crate::run_summary::RUN_SUMMARY_PATH;
 use anyhow::{anyhow, bail, Context, Result};
 use clap::Parser;
+use israel_prices::geocoding::Gazetteer;
 use metrics_exporter_prometheus::PrometheusBuilder;
+use rayon::prelude::*;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_with::serde_as;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::ErrorKind;
+use std::path::Path;
+use std::process::ExitCode;
 use std::sync::Mutex;
 use store::*;
 use tokio;
-use tracing::{debug, error, info, span, Level};
+use tracing::{debug, error, info, span, warn, Level};
 use tracing_subscriber::prelude::*;
 mod country_code;
+mod hebrew;
 mod nutrition;
 mod online_store_data;
+mod price;
+mod rate_limit;
 mod reqwest_utils;
+mod run_summary;
 mod sanitization;
 mod sqlite_utils;
+mod units;
 mod xml;
 
-fn run(command: &str) -> Result<()> {
-    let span = span!(Level::INFO, "Run command", command);
-    let _enter = span.enter();
-    debug!("Start");
-    let output = std::process::Command::new("bash")
-        .arg("-c")
-        .arg(command)
-        .output()?;
-    if !output.stdout.is_empty() {
-        debug!(output = String::from_utf8(output.stdout)?, "Output");
-    }
-    if !output.stderr.is_empty() {
-        error!(error = String::from_utf8(output.stderr)?, "Error",);
-    }
-    Ok(())
-}
-
 fn curate_data_raw() -> Result<()> {
     let span = span!(Level::INFO, "curate_data_raw");
     let _enter = span.enter();
     // Rami levy has two different stores files, one of them with a single store that is already present in the first stores file.
     info!("Deleting superfluous and incomplete Rami levy store file");
-    run("rm data_raw/rami_levy/storesfull* -f")?;
+    for entry in std::fs::read_dir("data_raw/rami_levy")
+        .into_iter()
+        .flatten()
+    {
+        let path = entry?.path();
+        if path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("storesfull"))
+        {
+            std::fs::remove_file(path)?;
+        }
+    }
 
-    info!("Deleting empty files");
-    run("find data_raw -type f -empty -print -delete")?;
+    info!("Deleting unusable files and decompressing the other ones");
+    let outcomes = curation::curate_dir(std::path::Path::new("data_raw"));
+    std::fs::write(
+        "curation_report.json",
+        serde_json::to_string_pretty(&outcomes)?,
+    )?;
 
-    info!("Deleting x1 files");
-    run("find data_raw -type f -name \"*.x1\" -print -delete")?;
+    Ok(())
+}
 
-    // Note: we need a loop here in case a gz file is malformed and causes an error
-    info!("Unzipping all files");
-    run("for file in data_raw/*/*.gz; do gunzip $file; done")?;
-    // run("gunzip data_raw/*/*.gz")?;
+#[derive(Default, Debug)]
+struct AggregatedData {
+    prices: Vec<ItemPrice>,
+    names: WeightedCounter,
+    manufacturer_names: WeightedCounter,
+    manufacture_country: WeightedCounter,
+    manufacturer_item_description: WeightedCounter,
+    chains: DataCounter<models::ChainId>,
+    unit_qty: WeightedCounter,
+    quantity: WeightedCounter,
+    unit_of_measure: WeightedCounter,
+    b_is_weighted: DataCounter<bool>,
+    qty_in_package: WeightedCounter,
+}
 
-    Ok(())
+impl AggregatedData {
+    fn merge(&mut self, other: AggregatedData) {
+        self.prices.extend(other.prices);
+        self.names.merge(other.names);
+        self.manufacturer_names.merge(other.manufacturer_names);
+        self.manufacture_country.merge(other.manufacture_country);
+        self.manufacturer_item_description
+            .merge(other.manufacturer_item_description);
+        self.chains.merge(other.chains);
+        self.unit_qty.merge(other.unit_qty);
+        self.quantity.merge(other.quantity);
+        self.unit_of_measure.merge(other.unit_of_measure);
+        self.b_is_weighted.merge(other.b_is_weighted);
+        self.qty_in_package.merge(other.qty_in_package);
+    }
+}
+
+fn merge_aggregated_data(
+    mut a: HashMap<ItemKey, AggregatedData>,
+    mut b: HashMap<ItemKey, AggregatedData>,
+) -> HashMap<ItemKey, AggregatedData> {
+    if a.len() < b.len() {
+        std::mem::swap(&mut a, &mut b);
+    }
+    for (key, data) in b {
+        a.entry(key).or_default().merge(data);
+    }
+    a
+}
+
+// Reads a PriceFull file, and applies on top of it the price updates published after it.
+fn read_prices(
+    price_path: &str,
+    price_updates: &HashMap<(String, String), Vec<FileInfo>>,
+) -> Result<(Option<models::Prices>, ParseReport)> {
+    let mut report = ParseReport::default();
+    debug!("Reading file: {price_path}");
+    let price = xml_to_standard::hande_price_file(price_path);
+    let Some(mut price) = report.record(price_path, price) else {
+        return Ok((None, report));
+    };
+    let base = price_path.parse::<FileInfo>()?;
+    price.file_date = base.datetime();
+    if let Some(updates) = price_updates.get(&(base.chain, base.store)) {
+        for update in updates.iter().filter(|update| update.date > base.date) {
+            debug!("Applying price update: {}", update.source);
+            let update_prices = xml_to_standard::hande_price_file(&update.source);
+            if let Some(mut update_prices) = report.record(&update.source, update_prices) {
+                for item in &mut update_prices.items {
+                    item.file_date = update.datetime();
+                }
+                price.apply_update(update_prices);
+            }
+        }
+    }
+    Ok((Some(price), report))
+}
+
+// Counts the values of the fields of the item, and returns its price, which the caller either
+// keeps with the item or writes to sqlite right away.
+fn aggregate_item(
+    items_aggregated_data: &mut HashMap<ItemKey, AggregatedData>,
+    chain_weights: &ChainWeights,
+    chain_id: models::ChainId,
+    store_id: models::StoreId,
+    item: models::Item,
+) -> (ItemKey, ItemPrice) {
+    let item_key = ItemKey::from_item_and_chain(&item, chain_id);
+
+    let data = items_aggregated_data.entry(item_key).or_default();
+    let quantity = units::parse_quantity(
+        &item.quantity,
+        &item.unit_qty,
+        &item.unit_of_measure,
+        &item.qty_in_package,
+    );
+    let price = ItemPrice {
+        chain_id,
+        store_id,
+        price: item.item_price,
+        unit_of_measure_price: item.unit_of_measure_price,
+        normalized_price: item
+            .item_price
+            .zip(quantity)
+            .and_then(|(price, quantity)| units::normalized_price(price, &quantity)),
+    };
+    let weight = chain_weights.get(chain_id);
+    data.names
+        .inc_weighted(sanitization::sanitize_name(&item.item_name), weight);
+    data.manufacturer_names
+        .inc_weighted(item.manufacturer_name, weight);
+    data.manufacture_country
+        .inc_weighted(item.manufacture_country, weight);
+    data.manufacturer_item_description
+        .inc_weighted(item.manufacturer_item_description, weight);
+    data.chains.inc(chain_id);
+    data.unit_qty.inc_weighted(item.unit_qty, weight);
+    data.quantity.inc_weighted(item.quantity, weight);
+    data.unit_of_measure
+        .inc_weighted(item.unit_of_measure, weight);
+    data.b_is_weighted.inc(item.b_is_weighted);
+    data.qty_in_package
+        .inc_weighted(item.qty_in_package, weight);
+    (item_key, price)
+}
+
+// Stores without coordinates in their stores file are located by their address.
+fn geocode_stores(chains: &mut [models::Chain], gazetteer: &Gazetteer) {
+    let (mut located, mut missing) = (0, 0);
+    for store in chains
+        .iter_mut()
+        .flat_map(|chain| chain.subchains.iter_mut())
+        .flat_map(|subchain| subchain.stores.iter_mut())
+        .filter(|store| store.latitude.is_none())
+    {
+        match gazetteer.locate(&store.city, &store.address) {
+            Some(location) => {
+                store.latitude = Some(location.latitude);
+                store.longitude = Some(location.longitude);
+                located += 1;
+            }
+            None => missing += 1,
+        }
+    }
+    info!("Located {located} stores, {missing} stores couldn't be located");
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -108,6 +260,16 @@ struct Args {
     #[arg(long)]
     no_build_item_infos: bool,
 
+    // How the value of an item field is chosen among the values of the chains, as
+    // field=policy, e.g. item_name=longest. See counter::CanonicalPolicy.
+    #[arg(long)]
+    canonical_policy: Vec<String>,
+
+    // How much the values of a chain count with the weighted policy, as chain_id=weight, e.g.
+    // 7290027600007=3. See counter::ChainWeights.
+    #[arg(long)]
+    chain_weight: Vec<String>,
+
     #[arg(long)]
     clear_files: bool,
 
@@ -120,12 +282,81 @@ struct Args {
     #[arg(long)]
     debug: bool,
 
+    // Download the Price files published after each PriceFull, and apply them on top of it.
+    #[arg(long)]
+    price_updates: bool,
+
+    // Aggregate the items of PriceFull files while they are read, instead of keeping all the
+    // prices in memory: the prices of each file are written to sqlite once it is read, and only
+    // the values of the fields of each item are kept until the end. Their memory still grows
+    // with the number of distinct items and values. Options that need all the prices at once,
+    // like --price-updates and --save-to-json, can't be used with it, and the item infos saved
+    // to json don't have prices.
+    #[arg(long)]
+    stream_prices: bool,
+
+    // The number of threads used to process files, all the cores are used by default.
+    #[arg(long, default_value = "0")]
+    jobs: usize,
+
     #[arg(long, default_value = "")]
     processing_filter: String,
 
     #[arg(long, default_value = "")]
     store: String,
 
+    // A CSV file with the columns city,street,latitude,longitude, used to locate the stores whose
+    // stores file has no coordinates.
+    #[arg(long)]
+    gazetteer: Option<String>,
+
+    // A TOML or JSON file listing the stores, instead of the built-in list.
+    #[arg(long)]
+    stores_config: Option<String>,
+
+    // Only download the files of these chain ids, e.g. 7290027600007.
+    #[arg(long, value_delimiter = ',')]
+    chains: Vec<String>,
+
+    // Only download the files of these store ids. Stores files are always downloaded.
+    #[arg(long, value_delimiter = ',')]
+    store_ids: Vec<String>,
+
+    // Only download these file types: stores, pricefull, promofull, price or promo.
+    #[arg(long, value_delimiter = ',')]
+    file_types: Vec<FileType>,
+
+    // Only download the files published in this date window, e.g. 2023-05-01.
+    #[arg(long)]
+    since: Option<chrono::NaiveDate>,
+
+    #[arg(long)]
+    until: Option<chrono::NaiveDate>,
+
+    // Download every file still hosted by the websites into archive/<store>/<yyyy-mm-dd>/,
+    // instead of only the most recent ones into data_raw. Use --since and --until to select
+    // the days to backfill.
+    #[arg(long)]
+    archive: bool,
+
+    // Send the requests to the websites through a local server that saves their responses as
+    // fixtures in this directory.
+    #[arg(long)]
+    record_http: Option<String>,
+
+    // Answer the requests to the websites with the fixtures recorded in this directory, instead
+    // of sending them.
+    #[arg(long)]
+    replay_http: Option<String>,
+
+    // The number of files expected from each store, unless its config sets expected_files.
+    #[arg(long, default_value = "0")]
+    min_files: usize,
+
+    // The exit code when a store couldn't be listed, or has fewer files than expected.
+    #[arg(long, default_value = "2")]
+    shortfall_exit_code: u8,
+
     #[arg(long)]
     fetch_shufersal_metadata: bool,
 
@@ -161,10 +392,18 @@ struct Args {
 
     #[arg(long, default_value = "0")]
     metadata_fetch_limit: usize,
+
+    // Add the processed prices to history.sqlite, which is kept across runs.
+    #[arg(long)]
+    save_price_history: bool,
+
+    // Print all the known price changes of a barcode from history.sqlite.
+    #[arg(long)]
+    print_price_history: Option<models::Barcode>,
 }
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     let prometheus = PrometheusBuilder::new()
         .install_recorder()
         .expect("failed to install prometheus exporter");
@@ -180,23 +419,51 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    let file_limit: Option<usize> = match args.minimal {
-        true => Some(5),
-        false => None,
+    if args.stream_prices
+        && (args.load_from_json
+            || args.save_to_json
+            || args.price_updates
+            || args.save_price_history
+            || args.fetch_shufersal_metadata)
+    {
+        bail!(
+            "--stream-prices doesn't keep the prices in memory, and can't be used with \
+            --load-from-json, --save-to-json, --price-updates, --save-price-history or \
+            --fetch-shufersal-metadata"
+        );
+    }
+
+    let selection = FileSelection {
+        file_limit: match args.minimal {
+            true => Some(5),
+            false => None,
+        },
+        price_updates: args.price_updates,
+        chains: args.chains.clone(),
+        store_ids: args.store_ids.clone(),
+        file_types: args.file_types.clone(),
+        since: args.since,
+        until: args.until,
+        archive: args.archive,
     };
 
+    let all_stores = match &args.stores_config {
+        Some(path) => load_store_configs(path)?,
+        None => get_store_configs(),
+    };
     let stores = match args.minimal {
-        false => get_store_configs(),
-        true => get_minimal_store_configs(),
+        false => all_stores.clone(),
+        true => get_minimal_store_configs(all_stores.clone()),
     };
     let stores = match args.debug {
         false => stores,
-        true => get_debug_store_configs(),
+        true => get_debug_store_configs(all_stores.clone()),
     };
     let stores = match args.store.as_str() {
         "" => stores,
         store => {
-            vec![get_store_config(store).ok_or(anyhow!("{store} is not an existing store name"))?]
+            vec![get_store_config(all_stores, store)
+                .ok_or(anyhow!("{store} is not an existing store name"))?]
         }
     };
 
@@ -222,17 +489,69 @@ async fn main() -> Result<()> {
         }
     }
 
+    if args.record_http.is_some() && args.replay_http.is_some() {
+        bail!("--record-http and --replay-http can't be used together");
+    }
+    if let Some(dir) = args.record_http.as_ref().or(args.replay_http.as_ref()) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let server = http_fixture::start(listener, Path::new(dir), args.record_http.is_some())?;
+        tokio::spawn(server);
+    }
+
+    let mut exit_code = ExitCode::SUCCESS;
     if !args.no_download {
-        store_data_download::download_all_stores_data(&stores, args.quick, file_limit, args.dir)
-            .await;
+        let summary = store_data_download::download_all_stores_data(
+            &stores, args.quick, &selection, args.dir,
+        )
+        .await;
+        let failures = summary.failures().collect::<Vec<_>>();
+        if !failures.is_empty() {
+            warn!("{} downloads failed permanently", failures.len());
+            for failure in failures {
+                warn!("{failure}");
+            }
+        }
+        print!("{}", summary.table());
+        if let Err(e) = summary.save(RUN_SUMMARY_PATH) {
+            error!("Couldn't write {RUN_SUMMARY_PATH}: {e}");
+        }
+        let expected = stores
+            .iter()
+            .map(|s| (s.name.clone(), s.expected_files.unwrap_or(args.min_files)))
+            .collect();
+        let shortfalls = summary.shortfalls(&expected);
+        for s in &shortfalls {
+            warn!(
+                "{}: {} files out of {} expected{}",
+                s.store,
+                s.available(),
+                expected[&s.store],
+                s.error
+                    .as_ref()
+                    .map_or(String::new(), |e| format!(", listing failed: {e}"))
+            );
+        }
+        if !shortfalls.is_empty() {
+            exit_code = ExitCode::from(args.shortfall_exit_code);
+        }
+    }
+    if args.archive {
+        // The archive is kept as downloaded, data_raw is left untouched.
+        info!("Archive mode, skipping curation and processing");
+        return Ok(exit_code);
     }
     if !args.no_curate {
         curate_data_raw()?;
     }
 
+    let mut report = ParseReport::default();
     if !args.no_process {
         let mut chains: Vec<models::Chain> = Vec::new();
         let mut prices: Vec<models::Prices> = Vec::new();
+        let mut promotions: Vec<models::Promotions> = Vec::new();
+        let mut items_aggregated_data: HashMap<ItemKey, AggregatedData> = HashMap::new();
+        let policies = counter::CanonicalPolicies::parse(&args.canonical_policy)?;
+        let chain_weights = counter::ChainWeights::parse(&args.chain_weight)?;
 
         if args.load_from_json {
             let chains_file = std::io::BufReader::new(std::fs::File::open("chains.json")?);
@@ -244,6 +563,14 @@ async fn main() -> Result<()> {
             info!("Reading prices from prices.json - this may take some time");
             prices = serde_json::from_reader(prices_file)?;
             info!("Read {} prices from prices.json", prices.len());
+
+            if std::path::Path::new("promotions.json").exists() {
+                let promotions_file =
+                    std::io::BufReader::new(std::fs::File::open("promotions.json")?);
+                info!("Reading promotions from promotions.json");
+                promotions = serde_json::from_reader(promotions_file)?;
+                info!("Read {} promotions from promotions.json", promotions.len());
+            }
         } else {
             info!("Starting processing of files");
             let paths = walkdir::WalkDir::new(std::path::Path::new("data_raw"))
@@ -256,37 +583,192 @@ async fn main() -> Result<()> {
                 .filter(|path| {
                     args.processing_filter == "" || path.contains(&args.processing_filter)
                 });
-
-            let (price_paths, stores_paths): (Vec<String>, Vec<String>) = paths.partition(|path| {
-                let filename = path.rsplit_once("/").unwrap().1;
-                filename.starts_with("Price") || filename.starts_with("price")
+            let manifest = manifest::Manifest::load(manifest::MANIFEST_PATH)?;
+
+            // Files are processed in parallel, but their results are kept in the order of their
+            // paths so that the output doesn't depend on the scheduling.
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(args.jobs)
+                .build()?;
+            let mut paths = paths.collect::<Vec<_>>();
+            paths.sort();
+            // Files that changed since they were downloaded are reported as failures.
+            let verified = pool.install(|| {
+                paths
+                    .par_iter()
+                    .map(|path| manifest.verify(path))
+                    .collect::<Vec<_>>()
             });
+            let paths = paths
+                .into_iter()
+                .zip(verified)
+                .filter_map(|(path, verified)| match verified {
+                    Ok(()) => Some(path),
+                    Err(e) => {
+                        report.record::<()>(&path, Err(e));
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+            let (promo_paths, paths): (Vec<String>, Vec<String>) =
+                paths.into_iter().partition(|path| {
+                    let filename = path.rsplit_once("/").unwrap().1;
+                    filename.starts_with("Promo") || filename.starts_with("promo")
+                });
+            let (price_paths, stores_paths): (Vec<String>, Vec<String>) =
+                paths.into_iter().partition(|path| {
+                    let filename = path.rsplit_once("/").unwrap().1;
+                    filename.starts_with("Price") || filename.starts_with("price")
+                });
             info!(
-                "There are {} stores files and {} prices files",
+                "There are {} stores files, {} prices files and {} promotions files",
                 stores_paths.len(),
-                price_paths.len()
+                price_paths.len(),
+                promo_paths.len()
             );
             info!("Starting to handle stores");
-            for store_path in stores_paths {
-                debug!("Reading file: {store_path}");
-                let chain = xml_to_standard::handle_stores_file(&store_path)?;
-                chains.push(chain);
+            let stores_results = pool.install(|| {
+                stores_paths
+                    .par_iter()
+                    .map(|store_path| {
+                        debug!("Reading file: {store_path}");
+                        xml_to_standard::handle_stores_file(store_path)
+                    })
+                    .collect::<Vec<_>>()
+            });
+            for (store_path, chain) in stores_paths.iter().zip(stores_results) {
+                if let Some(chain) = report.record(store_path, chain) {
+                    chains.push(chain);
+                }
+            }
+            if let Some(path) = &args.gazetteer {
+                geocode_stores(&mut chains, &Gazetteer::load(path)?);
             }
             if args.save_to_json {
                 info!("Writing chains.json");
                 std::fs::write("chains.json", serde_json::to_string(&chains).unwrap())?;
             }
             info!("Finished to handle stores, starting to handle prices");
-            for price_path in price_paths {
-                debug!("Reading file: {price_path}");
-                let price = xml_to_standard::hande_price_file(&price_path)?;
-                prices.push(price);
+            let (price_update_paths, price_paths): (Vec<String>, Vec<String>) =
+                price_paths.into_iter().partition(|path| {
+                    let filename = path.rsplit_once("/").unwrap().1;
+                    !filename.starts_with("PriceFull") && !filename.starts_with("pricefull")
+                });
+            let mut price_updates: HashMap<(String, String), Vec<FileInfo>> = HashMap::new();
+            if args.price_updates {
+                for path in price_update_paths {
+                    let file_info = path.parse::<FileInfo>()?;
+                    price_updates
+                        .entry((file_info.chain.clone(), file_info.store.clone()))
+                        .or_default()
+                        .push(file_info);
+                }
+                for updates in price_updates.values_mut() {
+                    updates.sort_by(|a, b| a.date.cmp(&b.date));
+                }
+            } else if !price_update_paths.is_empty() {
+                info!(
+                    "Ignoring {} price update files, use --price-updates to apply them",
+                    price_update_paths.len()
+                );
+            }
+            if args.stream_prices {
+                // Each thread aggregates the files it reads, and the aggregations are merged. The
+                // prices are written to sqlite file by file, only the counters of the fields of
+                // the items are kept in memory.
+                let saves_prices = (args.save_to_sqlite || !args.save_to_sqlite_only.is_empty())
+                    && (args.save_to_sqlite_only.is_empty()
+                        || args.save_to_sqlite_only.eq_ignore_ascii_case("prices"));
+                let streamed_prices = match saves_prices {
+                    true => Some(Mutex::new(sqlite_utils::create_streamed_prices()?)),
+                    false => None,
+                };
+                let (data, stream_report) = pool.install(|| {
+                    price_paths
+                        .par_iter()
+                        .try_fold(
+                            || (HashMap::new(), ParseReport::default()),
+                            |(data, mut report), price_path| {
+                                debug!("Reading file: {price_path}");
+                                // The items of a file are only kept when all of it could be read.
+                                let mut file_data = HashMap::new();
+                                let mut file_prices = Vec::new();
+                                let header = xml_to_standard::stream_price_file(
+                                    price_path,
+                                    |header, item| {
+                                        let (key, price) = aggregate_item(
+                                            &mut file_data,
+                                            &chain_weights,
+                                            header.chain_id,
+                                            header.store_id,
+                                            item,
+                                        );
+                                        file_prices.push((key.item_code, price));
+                                    },
+                                );
+                                if report.record(price_path, header).is_none() {
+                                    return Ok((data, report));
+                                }
+                                if let Some(connection) = &streamed_prices {
+                                    sqlite_utils::save_streamed_prices(
+                                        &mut connection.lock().unwrap(),
+                                        &file_prices,
+                                    )?;
+                                }
+                                anyhow::Ok((merge_aggregated_data(data, file_data), report))
+                            },
+                        )
+                        .try_reduce(
+                            || (HashMap::new(), ParseReport::default()),
+                            |(data, mut report), (other_data, other_report)| {
+                                report.merge(other_report);
+                                Ok((merge_aggregated_data(data, other_data), report))
+                            },
+                        )
+                })?;
+                items_aggregated_data = data;
+                report.merge(stream_report);
+            } else {
+                let prices_results = pool.install(|| {
+                    price_paths
+                        .par_iter()
+                        .map(|price_path| read_prices(price_path, &price_updates))
+                        .collect::<Result<Vec<_>>>()
+                })?;
+                for (price, prices_report) in prices_results {
+                    report.merge(prices_report);
+                    prices.extend(price);
+                }
             }
             if args.save_to_json {
                 info!("Writing prices.json");
                 std::fs::write("prices.json", serde_json::to_string(&prices).unwrap())?;
             }
-            info!("Finished to handle prices");
+            info!("Finished to handle prices, starting to handle promotions");
+            let promotions_results = pool.install(|| {
+                promo_paths
+                    .par_iter()
+                    .map(|promo_path| {
+                        debug!("Reading file: {promo_path}");
+                        xml_to_standard::handle_promo_file(promo_path)
+                    })
+                    .collect::<Vec<_>>()
+            });
+            for (promo_path, promotion) in promo_paths.iter().zip(promotions_results) {
+                if let Some(promotion) = report.record(promo_path, promotion) {
+                    promotions.push(promotion);
+                }
+            }
+            if args.save_to_json {
+                info!("Writing promotions.json");
+                std::fs::write(
+                    "promotions.json",
+                    serde_json::to_string(&promotions).unwrap(),
+                )?;
+            }
+            info!("Finished to handle promotions");
+            info!("Writing parse_report.json");
+            report.save("parse_report.json")?;
         }
 
         let mut item_infos = models::ItemInfos::default();
@@ -316,6 +798,10 @@ async fn main() -> Result<()> {
             rami_levy_item_codes.len()
         );
 
+        if args.save_price_history {
+            history::save_price_history(&prices)?;
+        }
+
         if args.load_item_infos_to_json {
             let item_infos_file = std::io::BufReader::new(std::fs::File::open("item_infos.json")?);
             info!("Reading item_infos from item_infos.json");
@@ -325,76 +811,64 @@ async fn main() -> Result<()> {
                 item_infos.data.len()
             );
         } else if !args.no_build_item_infos {
-            #[derive(Default, Debug)]
-            struct AggregatedData {
-                prices: Vec<ItemPrice>,
-                names: DataCounter<String>,
-                manufacturer_names: DataCounter<String>,
-                manufacture_country: DataCounter<String>,
-                manufacturer_item_description: DataCounter<String>,
-                chains: DataCounter<models::ChainId>,
-                unit_qty: DataCounter<String>,
-                quantity: DataCounter<String>,
-                unit_of_measure: DataCounter<String>,
-                b_is_weighted: DataCounter<bool>,
-                qty_in_package: DataCounter<String>,
-            }
-
-            let mut items_aggregated_data: HashMap<ItemKey, AggregatedData> = HashMap::new();
             info!("Starting to build Aggregated data");
             for price in prices {
                 for item in price.items {
-                    let item_key = ItemKey::from_item_and_chain(&item, price.chain_id);
-
-                    let data = items_aggregated_data
-                        .entry(item_key)
-                        .or_insert(AggregatedData::default());
-                    data.prices.push(ItemPrice {
-                        chain_id: price.chain_id,
-                        store_id: price.store_id,
-                        price: item.item_price,
-                        unit_of_measure_price: item.unit_of_measure_price,
-                    });
-                    data.names.inc(sanitization::sanitize_name(&item.item_name));
-                    data.manufacturer_names.inc(item.manufacturer_name);
-                    data.manufacture_country.inc(item.manufacture_country);
-                    data.manufacturer_item_description
-                        .inc(item.manufacturer_item_description);
-                    data.chains.inc(price.chain_id);
-                    data.unit_qty.inc(item.unit_qty);
-                    data.quantity.inc(item.quantity);
-                    data.unit_of_measure.inc(item.unit_of_measure);
-                    data.b_is_weighted.inc(item.b_is_weighted);
-                    data.qty_in_package.inc(item.qty_in_package);
+                    let (key, price) = aggregate_item(
+                        &mut items_aggregated_data,
+                        &chain_weights,
+                        price.chain_id,
+                        price.store_id,
+                        item,
+                    );
+                    items_aggregated_data
+                        .entry(key)
+                        .or_default()
+                        .prices
+                        .push(price);
                 }
             }
             info!("Finished to build Aggregated data");
-            for (key, data) in items_aggregated_data.into_iter() {
+            for (key, mut data) in items_aggregated_data.into_iter() {
+                // Streamed files may list an item twice, only its first price is kept.
+                data.prices.sort_by_key(|p| (p.chain_id, p.store_id));
+                data.prices.dedup_by_key(|p| (p.chain_id, p.store_id));
+                let select = |field, counter| policies.select(field, counter).context(key);
+                let unit_qty = select("unit_qty", &data.unit_qty)?;
+                let quantity = select("quantity", &data.quantity)?;
+                let unit_of_measure = select("unit_of_measure", &data.unit_of_measure)?;
+                let qty_in_package = select("qty_in_package", &data.qty_in_package)?;
+                let item_name = select("item_name", &data.names)?;
                 item_infos.data.insert(
                     key,
                     ItemInfo {
-                        item_name: counter::longest(&data.names).context(key)?.to_string(),
-                        manufacturer_name: counter::longest(&data.manufacturer_names)
-                            .context(key)?
+                        item_name: item_name.to_string(),
+                        manufacturer_name: select("manufacturer_name", &data.manufacturer_names)?
                             .to_string(),
-                        manufacturer_item_description: counter::longest(
+                        manufacturer_item_description: select(
+                            "manufacturer_item_description",
                             &data.manufacturer_item_description,
-                        )
-                        .context(key)?
+                        )?
                         .to_string(),
-                        manufacture_country: counter::longest(&data.manufacture_country)
-                            .context(key)?
-                            .to_string(),
-                        unit_qty: counter::longest(&data.unit_qty).context(key)?.to_string(),
-                        quantity: counter::longest(&data.quantity).context(key)?.to_string(),
-                        unit_of_measure: counter::longest(&data.unit_of_measure)
-                            .context(key)?
-                            .to_string(),
+                        manufacture_country: select(
+                            "manufacture_country",
+                            &data.manufacture_country,
+                        )?
+                        .to_string(),
+                        unit_qty: unit_qty.to_string(),
+                        quantity: quantity.to_string(),
+                        unit_of_measure: unit_of_measure.to_string(),
                         b_is_weighted: data.b_is_weighted.most_common().context(key)?.clone(),
-                        qty_in_package: counter::longest(&data.qty_in_package)
-                            .context(key)?
-                            .to_string(),
+                        qty_in_package: qty_in_package.to_string(),
+                        normalized_quantity: units::parse_quantity(
+                            quantity,
+                            unit_qty,
+                            unit_of_measure,
+                            qty_in_package,
+                        ),
                         prices: data.prices.clone(),
+                        policies: policies.to_map(),
+                        alternative_names: counter::alternatives(&data.names, item_name),
                     },
                 );
             }
@@ -408,7 +882,12 @@ async fn main() -> Result<()> {
             }
         }
         if args.save_to_sqlite || !args.save_to_sqlite_only.is_empty() {
-            sqlite_utils::save_to_sqlite(&chains, &item_infos.data, &args.save_to_sqlite_only)?;
+            sqlite_utils::save_to_sqlite(
+                &chains,
+                &item_infos.data,
+                &promotions,
+                &args.save_to_sqlite_only,
+            )?;
         }
         if args.fetch_shufersal_metadata {
             let num_of_chunks = shufersal_item_codes.len() / 1000;
@@ -424,6 +903,17 @@ async fn main() -> Result<()> {
             }
         }
     }
+    if let Some(item_code) = args.print_price_history {
+        for change in history::get_price_changes(item_code)? {
+            println!(
+                "{} chain {} store {}: {}",
+                change.file_date,
+                change.chain_id,
+                change.store_id,
+                change.price.map_or("-".to_string(), |p| p.to_string())
+            );
+        }
+    }
     if args.fetch_rami_levy_metadata {
         info!("Fetching rami levy metadata chunk");
         let rami_levy_metadata = online_store_data::fetch_rami_levy_metadata().await?;
@@ -498,5 +988,8 @@ async fn main() -> Result<()> {
         sqlite_utils::save_yochananof_metadata_to_sqlite(&yochananof_metadata)?;
     }
     info!("{}", prometheus.render());
-    Ok(())
+    if report.parsed > 0 || !report.failures.is_empty() {
+        report.log_summary();
+    }
+    Ok(exit_code)
 }
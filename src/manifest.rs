@@ -0,0 +1,179 @@
+use crate::file_info::FileInfo;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, ETAG, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::{info, warn};
+
+pub const MANIFEST_PATH: &str = "download_manifest.json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub url: String,
+    // Where the file was written, after decompression.
+    pub path: String,
+    pub file_type: Option<String>,
+    pub chain: Option<String>,
+    pub store: Option<String>,
+    pub date: Option<String>,
+    pub size: u64,
+    pub sha256: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub downloaded_at: DateTime<Utc>,
+}
+
+// The files downloaded by previous runs, by download destination.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    entries: HashMap<String, ManifestEntry>,
+    // The download destination of each written path, for verify.
+    #[serde(skip)]
+    by_path: HashMap<String, String>,
+}
+
+pub fn sha256(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+impl ManifestEntry {
+    pub fn new(url: &str, path: &Path, headers: &HeaderMap) -> Result<ManifestEntry> {
+        let content = std::fs::read(path)?;
+        let file_info = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.parse::<FileInfo>().ok());
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+        Ok(ManifestEntry {
+            url: url.to_string(),
+            path: path.display().to_string(),
+            file_type: file_info.as_ref().map(|fi| format!("{:?}", fi.file_type)),
+            chain: file_info.as_ref().map(|fi| fi.chain.clone()),
+            store: file_info.as_ref().map(|fi| fi.store.clone()),
+            date: file_info.as_ref().map(|fi| fi.date.clone()),
+            size: content.len() as u64,
+            sha256: sha256(&content),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            downloaded_at: Utc::now(),
+        })
+    }
+
+    // Only the size is compared, hashing every file before deciding to skip it is too slow.
+    fn is_present(&self) -> bool {
+        std::fs::metadata(&self.path).is_ok_and(|m| m.len() == self.size)
+    }
+}
+
+impl Manifest {
+    pub fn load(path: &str) -> Result<Manifest> {
+        if !Path::new(path).exists() {
+            info!("{path} doesn't exist, starting with an empty manifest");
+            return Ok(Manifest::default());
+        }
+        let mut manifest: Manifest =
+            serde_json::from_reader(std::io::BufReader::new(std::fs::File::open(path)?))?;
+        manifest.by_path = manifest
+            .entries
+            .iter()
+            .map(|(dest, entry)| (entry.path.clone(), dest.clone()))
+            .collect();
+        Ok(manifest)
+    }
+
+    // Entries of files that were deleted since they were downloaded are dropped.
+    pub fn save(&mut self, path: &str) -> Result<()> {
+        self.entries
+            .retain(|_, entry| Path::new(&entry.path).exists());
+        let entries = &self.entries;
+        self.by_path.retain(|_, dest| entries.contains_key(dest));
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    // Whether the file to be downloaded to dest was already downloaded by a previous run.
+    pub fn is_downloaded(&self, dest: &str) -> bool {
+        self.entries.get(dest).is_some_and(|e| e.is_present())
+    }
+
+    pub fn insert(&mut self, dest: &str, entry: ManifestEntry) {
+        let path = entry.path.clone();
+        if let Some(old) = self.entries.insert(dest.to_string(), entry) {
+            self.by_path.remove(&old.path);
+        }
+        self.by_path.insert(path, dest.to_string());
+    }
+
+    // Checks that a file wasn't modified since it was downloaded. Files that aren't in the
+    // manifest, e.g. downloaded by an older version, aren't checked.
+    pub fn verify(&self, path: &str) -> Result<()> {
+        let Some(entry) = self
+            .by_path
+            .get(path)
+            .and_then(|dest| self.entries.get(dest))
+        else {
+            return Ok(());
+        };
+        let sha256 = sha256(&std::fs::read(path)?);
+        if sha256 != entry.sha256 {
+            warn!(
+                "{path} has changed since it was downloaded from {}",
+                entry.url
+            );
+            bail!(
+                "Checksum mismatch: expected {}, found {sha256}",
+                entry.sha256
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let dir = std::env::temp_dir().join("test_manifest");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("PriceFull7290027600007-001-202306110800.xml");
+        std::fs::write(&path, "<Root/>").unwrap();
+        let path_str = path.display().to_string();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, "\"abc\"".parse().unwrap());
+        let entry = ManifestEntry::new("http://example.com/file.gz", &path, &headers).unwrap();
+        assert_eq!(entry.chain.as_deref(), Some("7290027600007"));
+        assert_eq!(entry.store.as_deref(), Some("1"));
+        assert_eq!(entry.file_type.as_deref(), Some("PriceFull"));
+        assert_eq!(entry.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(entry.size, 7);
+
+        let mut manifest = Manifest::default();
+        manifest.insert(&format!("{path_str}.gz"), entry);
+        assert!(manifest.is_downloaded(&format!("{path_str}.gz")));
+        assert!(!manifest.is_downloaded(&path_str));
+        assert!(manifest.verify(&path_str).is_ok());
+
+        std::fs::write(&path, "<Root></Root>").unwrap();
+        assert!(!manifest.is_downloaded(&format!("{path_str}.gz")));
+        assert!(manifest.verify(&path_str).is_err());
+
+        let manifest_path = dir.join("manifest.json").display().to_string();
+        manifest.save(&manifest_path).unwrap();
+        let mut loaded = Manifest::load(&manifest_path).unwrap();
+        assert!(loaded.verify(&path_str).is_err());
+        std::fs::remove_file(&path).unwrap();
+        loaded.save(&manifest_path).unwrap();
+        assert!(Manifest::load(&manifest_path).unwrap().entries.is_empty());
+    }
+}
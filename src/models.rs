@@ -1,10 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use chrono::NaiveDateTime;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_with::serde_as;
 
 use crate::nutrition::NutritionalValues;
+use crate::price::Price;
+use crate::units::Quantity;
 pub type Barcode = i64;
 pub type ChainId = i64;
 pub type SubchainId = i32;
@@ -26,13 +29,22 @@ pub struct Item {
     #[serde(rename = "weighted")]
     pub b_is_weighted: bool,
     pub qty_in_package: String,
-    #[serde(rename = "price")]
-    pub item_price: String,
-    pub unit_of_measure_price: String,
+    #[serde(
+        rename = "price",
+        default,
+        deserialize_with = "crate::price::deserialize_optional"
+    )]
+    pub item_price: Option<Price>,
+    #[serde(default, deserialize_with = "crate::price::deserialize_optional")]
+    pub unit_of_measure_price: Option<Price>,
     pub allow_discount: bool,
     #[serde(rename = "status")]
     pub item_status: i8,
     pub item_id: String,
+    // The date of the update file the item was read from, when it isn't the file of the
+    // store's prices.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_date: Option<NaiveDateTime>,
 
     #[serde(skip_serializing, skip_deserializing)]
     pub price_update_date: String,
@@ -46,11 +58,16 @@ pub struct Item {
 pub struct ItemPrice {
     pub chain_id: i64,
     pub store_id: i32,
-    pub price: String,
-    pub unit_of_measure_price: String,
+    #[serde(default, deserialize_with = "crate::price::deserialize_optional")]
+    pub price: Option<Price>,
+    #[serde(default, deserialize_with = "crate::price::deserialize_optional")]
+    pub unit_of_measure_price: Option<Price>,
+    // The price per 100g, per 100ml or per unit, see units::normalized_price.
+    #[serde(default, deserialize_with = "crate::price::deserialize_optional")]
+    pub normalized_price: Option<Price>,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Default, Serialize, Deserialize)]
 pub struct ItemInfo {
     pub item_name: String,
     pub manufacturer_name: String,
@@ -61,7 +78,14 @@ pub struct ItemInfo {
     pub unit_of_measure: String,
     pub b_is_weighted: bool,
     pub qty_in_package: String,
+    #[serde(default)]
+    pub normalized_quantity: Option<Quantity>,
     pub prices: Vec<ItemPrice>,
+    // The policy each field was chosen with, and the other names the chains use for the item.
+    #[serde(default)]
+    pub policies: BTreeMap<String, String>,
+    #[serde(default)]
+    pub alternative_names: Vec<String>,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
@@ -95,6 +119,51 @@ pub struct Prices {
     pub store_id: StoreId,
     pub verification_num: i32,
     pub items: Vec<Item>,
+    // The date of the PriceFull file these prices were read from, see Item::file_date.
+    #[serde(default)]
+    pub file_date: Option<NaiveDateTime>,
+}
+
+impl Prices {
+    // Price files only contain the items that changed since the previous file, so their items
+    // replace or are added to the ones already known for the store.
+    pub fn apply_update(&mut self, update: Prices) {
+        for item in update.items {
+            match self
+                .items
+                .binary_search_by_key(&item.item_code, |i| i.item_code)
+            {
+                Ok(i) => self.items[i] = item,
+                Err(i) => self.items.insert(i, item),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Promotion {
+    #[serde(rename = "id")]
+    pub promotion_id: String,
+    pub description: String,
+    pub start: Option<NaiveDateTime>,
+    pub end: Option<NaiveDateTime>,
+    #[serde(default, deserialize_with = "crate::price::deserialize_optional")]
+    pub discounted_price: Option<Price>,
+    pub discount_rate: Option<f64>,
+    pub min_qty: Option<f64>,
+    pub max_qty: Option<f64>,
+    pub club_only: bool,
+    pub gift_items: Vec<Barcode>,
+    pub item_codes: Vec<Barcode>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Promotions {
+    pub chain_id: ChainId,
+    pub subchain_id: SubchainId,
+    pub store_id: StoreId,
+    pub verification_num: i32,
+    pub promotions: Vec<Promotion>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -105,7 +174,14 @@ pub struct Store {
     pub store_name: String,
     pub address: String,
     pub city: String,
+    // The CBS code of the city, when it is known.
+    #[serde(default)]
+    pub city_id: Option<u32>,
     pub zip_code: String,
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
 }
 
 #[derive(Debug, Default)]
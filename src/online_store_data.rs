@@ -3,6 +3,7 @@ use crate::{
         self, Barcode, RamiLevyMetadata, ShufersalMetadata, VictoryMetadata, YochananofMetadata,
     },
     nutrition::{self, NutritionalValue, NutritionalValues},
+    rate_limit::{self, RateLimit},
     reqwest_utils::{self, get_to_text_with_retries, post_to_text_with_headers_with_retries},
 };
 use anyhow::{anyhow, Result};
@@ -15,6 +16,16 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tracing::{debug, info, instrument, span};
 
+// The online stores are scraped slowly, as they are not meant to be used this way.
+const SHUFERSAL_RATE_LIMIT: RateLimit = RateLimit {
+    requests_per_second: 10.0,
+    max_concurrency: 10,
+};
+const RAMI_LEVY_RATE_LIMIT: RateLimit = RateLimit {
+    requests_per_second: 1.0,
+    max_concurrency: 1,
+};
+
 fn create_selector(selectors: &str) -> Result<Selector> {
     Ok(Selector::parse(selectors).map_err(|_| anyhow!("couldn't build selector"))?)
 }
@@ -139,7 +150,15 @@ async fn fetch(item_code: Barcode) -> Result<(Barcode, ShufersalMetadata)> {
     let url = format!("https://www.shufersal.co.il/online/he/p/P_{item_code}/json");
     debug!("Fetching url {url} for itemcode {item_code}");
 
-    let document = reqwest::get(url).await?.text().await?;
+    let client = Client::new();
+    let document = reqwest_utils::send_with_retries(
+        &reqwest_utils::RetryPolicy::default(),
+        &url,
+        || client.get(&url),
+        reqwest::Response::text,
+        None,
+    )
+    .await?;
     let document = Html::parse_document(&document);
     let categories = get_categories(&document)?;
     let nutrition_info = get_nutrition_info(&document)?;
@@ -166,6 +185,7 @@ pub async fn fetch_shufersal_metadata(
 ) -> Result<HashMap<i64, ShufersalMetadata>> {
     let mut data = HashMap::new();
     let futures = FuturesUnordered::new();
+    rate_limit::configure("www.shufersal.co.il", SHUFERSAL_RATE_LIMIT);
 
     let item_codes = if limit == 0 {
         &item_codes[0..item_codes.len()]
@@ -255,6 +275,7 @@ pub async fn fetch_rami_levy_metadata() -> Result<HashMap<models::Barcode, RamiL
 
     let client = reqwest::Client::new();
     let url = "https://www.rami-levy.co.il/api/catalog";
+    rate_limit::configure("www.rami-levy.co.il", RAMI_LEVY_RATE_LIMIT);
     let mut all_products = HashMap::new();
     for department in departments {
         let response_str = reqwest_utils::post_to_text_with_retries(
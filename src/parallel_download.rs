@@ -1,92 +1,150 @@
-use bytes::Bytes;
+use crate::curation::write_decompressed;
+use crate::http_fixture;
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::published_prices::{self, PublishedPricesClient};
+use crate::reqwest_utils::{send_with_retries, RequestFailure, RetryPolicy};
 use futures::StreamExt;
 use metrics::increment_counter;
 use reqwest::{header::HeaderMap, Client};
-use std::fs::File;
-use std::io::Cursor;
-use std::sync::Arc;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use tokio::sync::Semaphore;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info};
 pub struct Download {
     pub store: String,
     pub path: String,
     pub headers: Option<HeaderMap>,
     pub dest: String,
+    // Refreshes the headers when the session expires during the download.
+    pub session: Option<Arc<PublishedPricesClient>>,
 }
-fn write_file(content: &Bytes, dest: &str) -> std::io::Result<()> {
-    let path = std::path::Path::new(&dest);
-    let prefix = path.parent().unwrap();
-    std::fs::create_dir_all(prefix).unwrap();
 
-    let mut file = File::create(&path)?;
-    let mut content = Cursor::new(content);
-    std::io::copy(&mut content, &mut file)?;
-    Ok(())
+#[derive(Debug, Default)]
+pub struct DownloadStats {
+    pub downloaded: usize,
+    // Files already downloaded by a previous run.
+    pub skipped: usize,
+    pub bytes: u64,
+    pub failures: Vec<RequestFailure>,
 }
-pub async fn parallel_download(downloads: Vec<Download>, download_semaphore: Arc<Semaphore>) {
+
+// Downloads the files, and returns how many were downloaded and the ones that failed permanently.
+pub async fn parallel_download(
+    downloads: Vec<Download>,
+    download_semaphore: Arc<Semaphore>,
+    manifest: Arc<Mutex<Manifest>>,
+) -> DownloadStats {
     info!("Starting parallel download");
+    let (skipped, downloads): (Vec<Download>, Vec<Download>) = downloads
+        .into_iter()
+        .partition(|download| manifest.lock().unwrap().is_downloaded(&download.dest));
+    if !skipped.is_empty() {
+        info!("Skipping {} files already downloaded", skipped.len());
+    }
+    let client = Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .unwrap();
+    let policy = RetryPolicy::default();
     futures::stream::iter(downloads)
         .map(|download| {
             increment_counter!("download_start", "store" => download.store.clone());
             let download_semaphore = download_semaphore.clone();
+            let manifest = manifest.clone();
+            let client = client.clone();
+            let policy = policy.clone();
             async move {
-                let mut should_retry = true;
-
-                while should_retry {
-                    let download_semaphore = download_semaphore.clone();
-
-                    let path = &download.path;
-                    let client = Client::builder()
-                        .timeout(std::time::Duration::from_secs(10))
-                        .build()
-                        .unwrap();
-                    let mut client = client.get(path);
-                    if let Some(headers) = &download.headers {
-                        client = client.headers(headers.clone())
-                    }
-                    let dest = &download.dest;
-                    let permit = match download_semaphore.acquire_owned().await {
-                        Ok(permit) => permit,
-                        Err(e) => {
-                            debug!("Error in writing {dest}: {e}");
-                            break;
-                        }
-                    };
-                    should_retry = match client.send().await {
-                        Ok(resp) => match resp.bytes().await {
-                            Ok(content) => match write_file(&content, dest) {
-                                Ok(()) => {
-                                    debug!("Success in writing {dest}");
-                                    increment_counter!("download_success", "store" => download.store.clone());
-                                    false
-                                }
-                                Err(e) => {
-                                    error!("Error in writing {dest}: {e}");
-                                    increment_counter!("download_failure: writing", "store" => download.store.clone());
-                                    false
-                                }
-                            },
-                            Err(e) => {
-                                warn!("ERROR reading {path}: {e}");
-                                increment_counter!("download_failure: reading", "store" => download.store.clone());
-                                true
+                let path = &download.path;
+                let dest = &download.dest;
+                let mut request_headers = download.headers.clone();
+                let mut refreshed = false;
+                let (headers, content) = loop {
+                    let response = send_with_retries(
+                        &policy,
+                        path,
+                        || {
+                            let request = client.get(http_fixture::url(path));
+                            match &request_headers {
+                                Some(headers) => request.headers(headers.clone()),
+                                None => request,
                             }
                         },
-                        Err(e) => {
-                            debug!("ERROR downloading {path}: {e}");
-                            increment_counter!("download_failure: downloading", "store" => download.store.clone());
-                            true
+                        |resp| async move {
+                            let expired = published_prices::is_login_page(resp.url());
+                            let headers = resp.headers().clone();
+                            resp.bytes().await.map(|content| (expired, headers, content))
+                        },
+                        Some(&download_semaphore),
+                    )
+                    .await;
+                    let failure = match (response, &download.session) {
+                        (Ok((false, headers, content)), _) => break (headers, content),
+                        (Ok((true, ..)), Some(session)) if !refreshed => {
+                            debug!("Session expired while downloading {path}");
+                            refreshed = true;
+                            let stale = request_headers.clone().unwrap_or_default();
+                            match session.refresh_headers(&stale).await {
+                                Ok(headers) => {
+                                    request_headers = Some(headers);
+                                    continue;
+                                }
+                                Err(e) => RequestFailure {
+                                    url: path.clone(),
+                                    attempts: 1,
+                                    status: None,
+                                    error: format!("Cannot refresh the session: {e}"),
+                                },
+                            }
                         }
+                        (Ok((true, ..)), _) => RequestFailure {
+                            url: path.clone(),
+                            attempts: 1,
+                            status: None,
+                            error: "Redirected to the login page".to_string(),
+                        },
+                        (Err(failure), _) => failure,
                     };
-                    if should_retry {
-                        debug!("Retrying {path}");
-                        increment_counter!("download retry", "store" => download.store.clone());
+                    increment_counter!("download_failure: downloading", "store" => download.store.clone());
+                    return Err(failure);
+                };
+                match write_decompressed(&content, Path::new(dest))
+                    .and_then(|written| ManifestEntry::new(path, &written, &headers))
+                {
+                    Ok(entry) => {
+                        debug!("Success in writing {dest}");
+                        manifest.lock().unwrap().insert(dest, entry);
+                        increment_counter!("download_success", "store" => download.store.clone());
+                        Ok(content.len() as u64)
+                    }
+                    Err(e) => {
+                        error!("Error in writing {dest}: {e}");
+                        increment_counter!("download_failure: writing", "store" => download.store.clone());
+                        Err(RequestFailure {
+                            url: path.clone(),
+                            attempts: 1,
+                            status: None,
+                            error: format!("Error in writing {dest}: {e}"),
+                        })
                     }
-                    drop(permit);
                 }
             }
         })
         .buffer_unordered(32)
-        .collect::<Vec<()>>()
-        .await;
+        .fold(
+            DownloadStats {
+                skipped: skipped.len(),
+                ..Default::default()
+            },
+            |mut stats, result| async move {
+                match result {
+                    Ok(bytes) => {
+                        stats.downloaded += 1;
+                        stats.bytes += bytes;
+                    }
+                    Err(failure) => stats.failures.push(failure),
+                }
+                stats
+            },
+        )
+        .await
 }
@@ -0,0 +1,77 @@
+use anyhow::Result;
+use itertools::Itertools;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::xml::ParseError;
+
+#[derive(Debug, Serialize)]
+pub struct FileFailure {
+    pub path: String,
+    pub kind: &'static str,
+    pub error: String,
+}
+
+// The outcome of parsing the files of a run. Files that fail to parse are skipped, so that a
+// single malformed file doesn't prevent the other chains from being processed.
+#[derive(Debug, Default, Serialize)]
+pub struct ParseReport {
+    pub parsed: usize,
+    pub failures: Vec<FileFailure>,
+}
+
+impl ParseReport {
+    pub fn record<T>(&mut self, path: &str, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.parsed += 1;
+                Some(value)
+            }
+            Err(e) => {
+                let kind = e
+                    .downcast_ref::<ParseError>()
+                    .map_or("other", ParseError::kind);
+                warn!("Skipping {path}: {e:#}");
+                self.failures.push(FileFailure {
+                    path: path.to_string(),
+                    kind,
+                    error: format!("{e:#}"),
+                });
+                None
+            }
+        }
+    }
+
+    // Failures are sorted by path, as files may be parsed in any order.
+    pub fn merge(&mut self, other: ParseReport) {
+        self.parsed += other.parsed;
+        self.failures.extend(other.failures);
+        self.failures.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn log_summary(&self) {
+        info!(
+            "Parsed {} files, {} failed",
+            self.parsed,
+            self.failures.len()
+        );
+        for (kind, failures) in &self
+            .failures
+            .iter()
+            .sorted_by_key(|f| f.kind)
+            .group_by(|f| f.kind)
+        {
+            let paths = failures.map(|f| f.path.as_str()).collect::<Vec<_>>();
+            info!(
+                "{} files failed with {kind}: {}",
+                paths.len(),
+                paths.join(", ")
+            );
+        }
+    }
+}
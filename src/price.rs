@@ -0,0 +1,230 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+// A price in agorot, so that prices can be compared and summed without floating point errors.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Price(pub i64);
+
+impl Price {
+    fn from_shekels(shekels: f64) -> Price {
+        Price((shekels * 100.0).round() as i64)
+    }
+
+    // Chains use empty strings, garbage and zero prices for unknown prices.
+    pub fn parse_optional(s: &str) -> Option<Price> {
+        s.parse::<Price>().ok().filter(|price| price.0 > 0)
+    }
+}
+
+impl FromStr for Price {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.replace(['\u{00A0}', ' ', '₪'], "");
+        // A comma is either a thousands separator ("1,234.50", "1,234") or a decimal one ("12,90").
+        let s = match s.rsplit_once(',') {
+            Some((_, after)) if s.contains('.') || after.len() == 3 => s.replace(',', ""),
+            Some(_) => s.replace(',', "."),
+            None => s,
+        };
+        let (negative, s) = match s.strip_prefix('-') {
+            Some(s) => (true, s),
+            None => (false, s.as_str()),
+        };
+        let (units, fraction) = s.split_once('.').unwrap_or((s, ""));
+        if units.is_empty() && fraction.is_empty() {
+            bail!("Empty price");
+        }
+        if !units
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            bail!("Invalid price: {s}");
+        }
+        let units = match units {
+            "" => 0,
+            units => units.parse::<i64>()?,
+        };
+        // Keep two digits, and round using the third one.
+        let digit = |i: usize| {
+            fraction
+                .as_bytes()
+                .get(i)
+                .map_or(0, |digit| i64::from(digit - b'0'))
+        };
+        let agorot = units
+            .checked_mul(100)
+            .and_then(|agorot| {
+                agorot.checked_add(digit(0) * 10 + digit(1) + i64::from(digit(2) >= 5))
+            })
+            .ok_or_else(|| anyhow!("Price is too large: {s}"))?;
+        Ok(Price(if negative { -agorot } else { agorot }))
+    }
+}
+
+impl std::fmt::Display for Price {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        write!(f, "{sign}{}.{:02}", self.0.abs() / 100, self.0.abs() % 100)
+    }
+}
+
+impl Serialize for Price {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+struct PriceVisitor;
+
+impl<'de> de::Visitor<'de> for PriceVisitor {
+    type Value = Price;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a price in shekels")
+    }
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Price, E> {
+        v.parse().map_err(E::custom)
+    }
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Price, E> {
+        v.checked_mul(100)
+            .map(Price)
+            .ok_or_else(|| E::custom(format!("Price is too large: {v}")))
+    }
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Price, E> {
+        i64::try_from(v)
+            .ok()
+            .and_then(|v| v.checked_mul(100))
+            .map(Price)
+            .ok_or_else(|| E::custom(format!("Price is too large: {v}")))
+    }
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Price, E> {
+        Ok(Price::from_shekels(v))
+    }
+}
+
+impl<'de> Deserialize<'de> for Price {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(PriceVisitor)
+    }
+}
+
+// Files written before prices were parsed have empty strings for unknown prices.
+pub fn deserialize_optional<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Price>, D::Error> {
+    struct OptionalPriceVisitor;
+
+    impl<'de> de::Visitor<'de> for OptionalPriceVisitor {
+        type Value = Option<Price>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a price in shekels, or nothing")
+        }
+        fn visit_unit<E: de::Error>(self) -> Result<Option<Price>, E> {
+            Ok(None)
+        }
+        fn visit_none<E: de::Error>(self) -> Result<Option<Price>, E> {
+            Ok(None)
+        }
+        fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Option<Price>, D::Error> {
+            deserialize_optional(d)
+        }
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Option<Price>, E> {
+            match v.trim() {
+                "" => Ok(None),
+                v => PriceVisitor.visit_str(v).map(Some),
+            }
+        }
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<Option<Price>, E> {
+            PriceVisitor.visit_i64(v).map(Some)
+        }
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Option<Price>, E> {
+            PriceVisitor.visit_u64(v).map(Some)
+        }
+        fn visit_f64<E: de::Error>(self, v: f64) -> Result<Option<Price>, E> {
+            PriceVisitor.visit_f64(v).map(Some)
+        }
+    }
+
+    deserializer.deserialize_any(OptionalPriceVisitor)
+}
+
+impl ToSql for Price {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0))
+    }
+}
+
+// Databases written before prices were stored as integers contain the original text.
+impl FromSql for Price {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Integer(agorot) => Ok(Price(agorot)),
+            ValueRef::Real(shekels) => Ok(Price::from_shekels(shekels)),
+            ValueRef::Text(text) => std::str::from_utf8(text)
+                .map_err(|e| anyhow!(e))
+                .and_then(str::parse)
+                .map_err(|e| FromSqlError::Other(e.into())),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_price() {
+        assert_eq!("12.90".parse::<Price>().unwrap(), Price(1290));
+        assert_eq!("12.9".parse::<Price>().unwrap(), Price(1290));
+        assert_eq!(" 5 ".parse::<Price>().unwrap(), Price(500));
+        assert_eq!("12,90".parse::<Price>().unwrap(), Price(1290));
+        assert_eq!("1,234.50".parse::<Price>().unwrap(), Price(123450));
+        assert_eq!("1,234".parse::<Price>().unwrap(), Price(123400));
+        assert_eq!("3.995".parse::<Price>().unwrap(), Price(400));
+        assert_eq!(".50".parse::<Price>().unwrap(), Price(50));
+        assert_eq!("0.00".parse::<Price>().unwrap(), Price(0));
+        assert!("".parse::<Price>().is_err());
+        assert!("abc".parse::<Price>().is_err());
+        assert!("99999999999999999999".parse::<Price>().is_err());
+        assert!("92233720368547758.08".parse::<Price>().is_err());
+    }
+
+    #[test]
+    fn test_parse_optional_price() {
+        assert_eq!(Price::parse_optional("7.50 "), Some(Price(750)));
+        assert_eq!(Price::parse_optional("0.00"), None);
+        assert_eq!(Price::parse_optional(""), None);
+    }
+
+    #[test]
+    fn test_display_and_serde() {
+        assert_eq!(Price(1205).to_string(), "12.05");
+        assert_eq!(Price(-50).to_string(), "-0.50");
+        assert_eq!(serde_json::to_string(&Price(1290)).unwrap(), "\"12.90\"");
+        assert_eq!(
+            serde_json::from_str::<Price>("\"12.90\"").unwrap(),
+            Price(1290)
+        );
+        assert_eq!(serde_json::from_str::<Price>("12.9").unwrap(), Price(1290));
+        assert!(serde_json::from_str::<Price>("92233720368547759").is_err());
+
+        #[derive(Deserialize)]
+        struct Item {
+            #[serde(default, deserialize_with = "deserialize_optional")]
+            price: Option<Price>,
+        }
+        let price = |json: &str| serde_json::from_str::<Item>(json).unwrap().price;
+        assert_eq!(price(r#"{"price": ""}"#), None);
+        assert_eq!(price(r#"{"price": null}"#), None);
+        assert_eq!(price("{}"), None);
+        assert_eq!(price(r#"{"price": "12.90"}"#), Some(Price(1290)));
+        assert_eq!(price(r#"{"price": 5}"#), Some(Price(500)));
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::hebrew;
+use crate::models::{ItemInfo, ItemKey};
+use crate::units::{self, Quantity};
+
+// Pairs scoring less than this are not considered the same product.
+const MIN_CONFIDENCE: f64 = 0.6;
+// Tokens shared by more items than this, e.g. "חלב", don't help to find candidates.
+const MAX_TOKEN_ITEMS: usize = 200;
+// GS1 barcodes of Israeli manufacturers start with 729 and a 4 digits company code.
+const COMPANY_PREFIX_DIGITS: u32 = 7;
+
+// The product an item belongs to. Items of the same product share the same group, which is
+// the key of one of them: the item with a global barcode when there is one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProductGroup {
+    pub group: ItemKey,
+    // How confident we are that the item is the product of its group, between 0 and 1.
+    pub confidence: f64,
+}
+
+struct Signature {
+    tokens: Vec<String>,
+    manufacturer: String,
+    brand: Option<&'static str>,
+    quantity: Option<Quantity>,
+}
+
+impl Signature {
+    fn new(info: &ItemInfo) -> Signature {
+        let name = hebrew::parse_item_name(&info.item_name);
+        Signature {
+            tokens: name_tokens(&name.name),
+            manufacturer: hebrew::normalize(&info.manufacturer_name).to_lowercase(),
+            brand: name.brand,
+            quantity: info.normalized_quantity.or(name.quantity),
+        }
+    }
+}
+
+// The words of a name normalized by hebrew::parse_item_name, without amounts and units:
+// "שמנת חמוצה 15% 200 מיליליטר" -> [שמנת, חמוצה].
+fn name_tokens(name: &str) -> Vec<String> {
+    let mut tokens = name
+        .to_lowercase()
+        .split(' ')
+        .map(|t| {
+            t.chars()
+                .filter(|c| c.is_alphanumeric())
+                .collect::<String>()
+        })
+        .filter(|t| !t.is_empty() && !t.chars().any(|c| c.is_ascii_digit()))
+        .filter(|t| units::parse_unit(t).is_none())
+        .collect::<Vec<_>>();
+    tokens.dedup();
+    tokens
+}
+
+// Chains cut names at a fixed length, so a token matches the tokens it is the start of.
+fn tokens_match(a: &str, b: &str) -> bool {
+    let (short, long) = match a.len() < b.len() {
+        true => (a, b),
+        false => (b, a),
+    };
+    long.starts_with(short) && short.chars().count() >= 2
+}
+
+fn name_similarity(a: &[String], b: &[String]) -> f64 {
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    let matched = |from: &[String], to: &[String]| {
+        from.iter()
+            .filter(|t| to.iter().any(|u| tokens_match(t, u)))
+            .count()
+    };
+    (matched(a, b) + matched(b, a)) as f64 / (a.len() + b.len()) as f64
+}
+
+fn company_prefix(key: &ItemKey) -> Option<i64> {
+    let digits = key.item_code.checked_ilog10()? + 1;
+    match key.chain_id.is_none() && digits == 13 {
+        true => Some(key.item_code / 10i64.pow(digits - COMPANY_PREFIX_DIGITS)),
+        false => None,
+    }
+}
+
+fn same_quantity(a: &Quantity, b: &Quantity) -> bool {
+    a.unit == b.unit && (a.amount - b.amount).abs() <= 0.01 * a.amount.max(b.amount)
+}
+
+// The confidence that two items are the same product.
+fn score(a: (&ItemKey, &Signature), b: (&ItemKey, &Signature)) -> f64 {
+    let ((key_a, a), (key_b, b)) = (a, b);
+    let mut score = name_similarity(&a.tokens, &b.tokens);
+    match (a.quantity, b.quantity) {
+        (Some(qa), Some(qb)) if !same_quantity(&qa, &qb) => return 0.0,
+        (Some(_), Some(_)) => score += 0.1,
+        _ => score -= 0.1,
+    }
+    if !a.manufacturer.is_empty() && !b.manufacturer.is_empty() {
+        score += match a.manufacturer == b.manufacturer {
+            true => 0.1,
+            false => -0.2,
+        };
+    }
+    if let (Some(brand_a), Some(brand_b)) = (a.brand, b.brand) {
+        score += match brand_a == brand_b {
+            true => 0.1,
+            false => -0.2,
+        };
+    }
+    if let (Some(pa), Some(pb)) = (company_prefix(key_a), company_prefix(key_b)) {
+        score += match pa == pb {
+            true => 0.1,
+            false => -0.2,
+        };
+    }
+    score.clamp(0.0, 1.0)
+}
+
+struct Groups {
+    parents: Vec<usize>,
+    has_barcode: Vec<bool>,
+    chains: Vec<HashSet<i64>>,
+}
+
+impl Groups {
+    fn root(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    // Two items with different global barcodes are different products, and so are two internal
+    // items of the same chain, so groups holding them are never merged.
+    fn union(&mut self, a: usize, b: usize) -> bool {
+        let (a, b) = (self.root(a), self.root(b));
+        if a == b
+            || (self.has_barcode[a] && self.has_barcode[b])
+            || !self.chains[a].is_disjoint(&self.chains[b])
+        {
+            return false;
+        }
+        self.parents[b] = a;
+        self.has_barcode[a] |= self.has_barcode[b];
+        let chains = std::mem::take(&mut self.chains[b]);
+        self.chains[a].extend(chains);
+        true
+    }
+}
+
+// Groups the items of the chains into products. Items with a global barcode are already the
+// same product in every chain; items with internal codes are matched to them and to the
+// internal items of the other chains by their names, manufacturers, quantities and barcodes.
+// Only the items grouped with others are returned.
+pub fn group_products(item_infos: &HashMap<ItemKey, ItemInfo>) -> HashMap<ItemKey, ProductGroup> {
+    let mut keys = item_infos.keys().copied().collect::<Vec<_>>();
+    keys.sort_by_key(|key| (key.chain_id.is_some(), key.chain_id, key.item_code));
+    let signatures = keys
+        .iter()
+        .map(|key| Signature::new(&item_infos[key]))
+        .collect::<Vec<_>>();
+
+    let mut token_items: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, signature) in signatures.iter().enumerate() {
+        for token in &signature.tokens {
+            token_items.entry(token).or_default().push(i);
+        }
+    }
+
+    // Each internal item is compared to the items sharing its rarest token.
+    let mut pairs = Vec::new();
+    for (i, key) in keys.iter().enumerate() {
+        if key.chain_id.is_none() {
+            continue;
+        }
+        let Some(candidates) = signatures[i]
+            .tokens
+            .iter()
+            .map(|token| &token_items[token.as_str()])
+            .filter(|items| items.len() <= MAX_TOKEN_ITEMS)
+            .min_by_key(|items| items.len())
+        else {
+            continue;
+        };
+        let mut seen = HashSet::new();
+        for &j in candidates {
+            // Internal items of the same chain are different products.
+            if j == i || keys[j].chain_id == key.chain_id || !seen.insert(j) {
+                continue;
+            }
+            let score = score((key, &signatures[i]), (&keys[j], &signatures[j]));
+            if score >= MIN_CONFIDENCE {
+                pairs.push((score, i, j));
+            }
+        }
+    }
+
+    // The best matches are grouped first, and a group is as confident as its weakest match.
+    pairs.sort_by(|a, b| b.0.total_cmp(&a.0).then((a.1, a.2).cmp(&(b.1, b.2))));
+    let mut groups = Groups {
+        parents: (0..keys.len()).collect(),
+        has_barcode: keys.iter().map(|key| key.chain_id.is_none()).collect(),
+        chains: keys
+            .iter()
+            .map(|key| key.chain_id.into_iter().collect())
+            .collect(),
+    };
+    let mut confidences: HashMap<usize, f64> = HashMap::new();
+    for (score, i, j) in pairs {
+        let (root_i, root_j) = (groups.root(i), groups.root(j));
+        let confidence = [root_i, root_j]
+            .iter()
+            .filter_map(|root| confidences.get(root))
+            .fold(score, |a, &b| a.min(b));
+        if groups.union(i, j) {
+            confidences.insert(groups.root(i), confidence);
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..keys.len() {
+        let root = groups.root(i);
+        members.entry(root).or_default().push(i);
+    }
+    let mut result = HashMap::new();
+    for (root, items) in members.into_iter().filter(|(_, items)| items.len() > 1) {
+        // Items are sorted with global barcodes first, so the group is named after one of them.
+        let group = keys[*items.iter().min().unwrap()];
+        for i in items {
+            result.insert(
+                keys[i],
+                ProductGroup {
+                    group,
+                    confidence: confidences[&root],
+                },
+            );
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::Unit;
+
+    fn item(name: &str, manufacturer: &str, quantity: &str, unit: &str) -> ItemInfo {
+        ItemInfo {
+            item_name: name.to_string(),
+            manufacturer_name: manufacturer.to_string(),
+            manufacture_country: String::new(),
+            manufacturer_item_description: String::new(),
+            unit_qty: unit.to_string(),
+            quantity: quantity.to_string(),
+            unit_of_measure: String::new(),
+            b_is_weighted: false,
+            qty_in_package: String::new(),
+            normalized_quantity: units::parse_quantity(quantity, unit, "", ""),
+            prices: Vec::new(),
+            policies: Default::default(),
+            alternative_names: Vec::new(),
+        }
+    }
+
+    fn key(item_code: i64, chain_id: Option<i64>) -> ItemKey {
+        ItemKey {
+            item_code,
+            chain_id,
+        }
+    }
+
+    #[test]
+    fn test_group_products() {
+        assert_eq!(
+            name_tokens("טרה שמנת חמוצה 15% 200 מיליליטר"),
+            vec!["טרה", "שמנת", "חמוצה"]
+        );
+        // Items without quantity fields fall back to the quantity in their name.
+        assert_eq!(
+            Signature::new(&item("שמנת חמוצה 200מל", "", "", "")).quantity,
+            Some(Quantity {
+                amount: 200.0,
+                unit: Unit::Milliliter
+            })
+        );
+        let barcode = key(7290000000017, None);
+        let other_barcode = key(7290000000024, None);
+        let shufersal = key(123, Some(7290027600007));
+        let rami_levy = key(456, Some(7290058140886));
+        let rami_levy_other = key(789, Some(7290058140886));
+        let victory = key(123, Some(7290696200003));
+        let osher_ad = key(55, Some(7290103152017));
+        let yochananof = key(66, Some(7290803800003));
+        let yochananof_other = key(77, Some(7290803800003));
+        let item_infos = HashMap::from([
+            (
+                barcode,
+                item("שמנת חמוצה 15% 200 מ\"ל", "טרה", "200", "מ\"ל"),
+            ),
+            (
+                other_barcode,
+                item("שמנת חמוצה 15% 200 מ\"ל", "טרה", "200", "מ\"ל"),
+            ),
+            (shufersal, item("שמנת חמוצה 15% טרה", "טרה", "200", "מל")),
+            (rami_levy, item("שמנת חמוצה 200מל", "", "0.2", "ליטר")),
+            (
+                rami_levy_other,
+                item("שמנת חמוצה 15% 500מל", "", "500", "מל"),
+            ),
+            (
+                victory,
+                item("פיצה מרגריטה עם זיתים", "דלישס", "400", "גרם"),
+            ),
+            (osher_ad, item("חלב טרי 3% בקרטון", "תנובה", "1", "ליטר")),
+            (yochananof, item("חלב טרי 3% בקרטון", "תנובה", "1", "ליטר")),
+            (
+                yochananof_other,
+                item("חלב טרי 3% בקרטון", "תנובה", "1", "ליטר"),
+            ),
+        ]);
+        let groups = group_products(&item_infos);
+
+        let group = groups[&shufersal];
+        assert!(group.group == barcode || group.group == other_barcode);
+        assert!(group.confidence >= MIN_CONFIDENCE && group.confidence <= 1.0);
+        // The global barcodes stay distinct products.
+        assert_ne!(
+            groups.get(&other_barcode).map(|g| g.group),
+            groups.get(&barcode).map(|g| g.group)
+        );
+        assert_eq!(groups[&rami_levy].group.chain_id, None);
+        // Different quantities, or different names.
+        assert_eq!(groups.get(&rami_levy_other), None);
+        assert_eq!(groups.get(&victory), None);
+        // Both items of Yochananof match the item of Osher Ad, but only one joins its group.
+        let group = groups[&osher_ad].group;
+        assert_ne!(
+            groups.get(&yochananof).map(|g| g.group),
+            groups.get(&yochananof_other).map(|g| g.group)
+        );
+        assert!([yochananof, yochananof_other]
+            .iter()
+            .any(|key| groups.get(key).map(|g| g.group) == Some(group)));
+    }
+}
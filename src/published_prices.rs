@@ -0,0 +1,443 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::NaiveDate;
+use lazy_static::lazy_static;
+use reqwest::{header, Client, Response, StatusCode, Url};
+use scraper::{Html, Selector};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info};
+
+use crate::file_info::{FileSelection, FileType};
+use crate::http_fixture;
+use crate::rate_limit;
+use crate::store_data_download::get_cookie_from_resp;
+
+const BASE_URL: &str = "https://url.publishedprices.co.il";
+const PAGE_SIZE: usize = 1000;
+
+lazy_static! {
+    // Stores sharing a user share its session.
+    static ref CLIENTS: Mutex<HashMap<String, Arc<PublishedPricesClient>>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Session {
+    cookie: String,
+    csrftoken: String,
+}
+
+// A file or a folder of the listing.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Entry {
+    pub fname: String,
+    #[serde(default)]
+    pub ftype: String,
+}
+
+impl Entry {
+    pub fn is_folder(&self) -> bool {
+        self.ftype == "folder"
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Listing {
+    i_total_display_records: usize,
+    aa_data: Vec<Entry>,
+}
+
+// Filters applied by the website, so that only the matching files are listed.
+#[derive(Debug, Clone, Default)]
+pub struct Search {
+    pub pattern: Option<String>,
+    pub date: Option<NaiveDate>,
+}
+
+impl Search {
+    pub fn for_selection(selection: &FileSelection) -> Search {
+        let pattern = match selection.file_types.as_slice() {
+            [FileType::PriceFull] => Some("PriceFull"),
+            [FileType::PromoFull] => Some("PromoFull"),
+            [FileType::StoresFull] => Some("Stores"),
+            _ => None,
+        };
+        let date = match (selection.since, selection.until) {
+            (Some(since), Some(until)) if since == until => Some(since),
+            _ => None,
+        };
+        Search {
+            pattern: pattern.map(str::to_string),
+            date,
+        }
+    }
+}
+
+// The search parameters of a listing: the global search, and the search of each column
+// (fname, typeLabel, size, ftime and the actions).
+#[derive(Debug, Default)]
+struct Query {
+    search: String,
+    columns: [String; 5],
+}
+
+impl Query {
+    // The files are named after their type and date.
+    fn files(search: &Search) -> Query {
+        let mut query = Query {
+            search: search.pattern.clone().unwrap_or_default(),
+            ..Default::default()
+        };
+        if let Some(date) = search.date {
+            query.columns[0] = date.format("%Y%m%d").to_string();
+        }
+        query
+    }
+
+    fn folders() -> Query {
+        let mut query = Query::default();
+        query.columns[1] = "folder".to_string();
+        query
+    }
+
+    fn is_empty(&self) -> bool {
+        self.search.is_empty() && self.columns.iter().all(String::is_empty)
+    }
+}
+
+// The login page, where requests end up once the session expired.
+pub fn is_login_page(url: &Url) -> bool {
+    url.path().trim_end_matches('/').ends_with("/login")
+}
+
+fn extract_csrf(html: &str) -> Result<String> {
+    let document = Html::parse_document(html);
+    let selector = Selector::parse("meta[name=\"csrftoken\"]").unwrap();
+    document
+        .select(&selector)
+        .next()
+        .and_then(|meta| meta.value().attr("content"))
+        .map(str::to_string)
+        .ok_or(anyhow!("Cannot extract csrf token"))
+}
+
+pub struct PublishedPricesClient {
+    username: String,
+    password: String,
+    client: Client,
+    page_size: usize,
+    session: tokio::sync::Mutex<Option<Session>>,
+}
+
+impl PublishedPricesClient {
+    pub fn new(username: &str, password: &str) -> Result<PublishedPricesClient> {
+        Ok(PublishedPricesClient {
+            username: username.to_string(),
+            password: password.to_string(),
+            client: Client::builder().cookie_store(true).build()?,
+            page_size: PAGE_SIZE,
+            session: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn with_page_size(self, page_size: usize) -> PublishedPricesClient {
+        PublishedPricesClient { page_size, ..self }
+    }
+
+    // The client of a user, which logs in only once per run.
+    pub fn for_user(username: &str, password: &str) -> Result<Arc<PublishedPricesClient>> {
+        let mut clients = CLIENTS.lock().unwrap();
+        if let Some(client) = clients.get(username) {
+            return Ok(client.clone());
+        }
+        let client = Arc::new(PublishedPricesClient::new(username, password)?);
+        clients.insert(username.to_string(), client.clone());
+        Ok(client)
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder, url: &str) -> Result<Response> {
+        let _host_permit = rate_limit::acquire(url).await;
+        Ok(request.send().await?)
+    }
+
+    async fn login(&self) -> Result<Session> {
+        info!("Logging in to publishedprices as {}", self.username);
+        let url = format!("{BASE_URL}/login");
+        let resp = self
+            .send(self.client.get(http_fixture::url(&url)), &url)
+            .await?;
+        let csrftoken = extract_csrf(&resp.text().await?)?;
+
+        let url = format!("{BASE_URL}/login/user");
+        let request = self.client.post(http_fixture::url(&url)).form(&[
+            ("r", ""),
+            ("username", &self.username),
+            ("password", &self.password),
+            ("Submit", "Sign in"),
+            ("csrftoken", &csrftoken),
+        ]);
+        let resp = self.send(request, &url).await?;
+        let cookie = get_cookie_from_resp(&resp)?;
+        let csrftoken = extract_csrf(&resp.text().await?)?;
+        Ok(Session { cookie, csrftoken })
+    }
+
+    // The token is refreshed from the file manager page, as long as the session is valid.
+    async fn refresh_csrf(&self, stale: &Session) -> Result<Session> {
+        let url = format!("{BASE_URL}/file");
+        let request = self
+            .client
+            .get(http_fixture::url(&url))
+            .header(header::COOKIE, &stale.cookie);
+        let resp = self.send(request, &url).await?;
+        if is_login_page(resp.url()) || !resp.status().is_success() {
+            bail!("Session of {} expired", self.username);
+        }
+        Ok(Session {
+            cookie: stale.cookie.clone(),
+            csrftoken: extract_csrf(&resp.text().await?)?,
+        })
+    }
+
+    async fn session(&self) -> Result<Session> {
+        let mut session = self.session.lock().await;
+        if let Some(session) = session.as_ref() {
+            return Ok(session.clone());
+        }
+        let new_session = self.login().await?;
+        *session = Some(new_session.clone());
+        Ok(new_session)
+    }
+
+    // Replaces a session that stopped working. Concurrent callers holding the same stale
+    // session get the session that replaced it.
+    async fn refresh(&self, stale: &Session) -> Result<Session> {
+        let mut session = self.session.lock().await;
+        if let Some(current) = session.as_ref().filter(|s| *s != stale) {
+            return Ok(current.clone());
+        }
+        let new_session = match self.refresh_csrf(stale).await {
+            Ok(new_session) => new_session,
+            Err(e) => {
+                debug!("{e}, logging in again");
+                self.login().await?
+            }
+        };
+        *session = Some(new_session.clone());
+        Ok(new_session)
+    }
+
+    fn headers_for(session: &Session) -> header::HeaderMap {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded".parse().unwrap(),
+        );
+        headers.insert(header::COOKIE, session.cookie.parse().unwrap());
+        headers
+    }
+
+    // The headers to download files with.
+    pub async fn headers(&self) -> Result<header::HeaderMap> {
+        Ok(Self::headers_for(&self.session().await?))
+    }
+
+    // Called when a download ended up on the login page with the given headers.
+    pub async fn refresh_headers(&self, stale: &header::HeaderMap) -> Result<header::HeaderMap> {
+        let stale_cookie = stale
+            .get(header::COOKIE)
+            .and_then(|c| c.to_str().ok())
+            .unwrap_or("");
+        let current = self.session().await?;
+        let stale = match current.cookie == stale_cookie {
+            true => current,
+            // The session was already replaced.
+            false => return Ok(Self::headers_for(&current)),
+        };
+        Ok(Self::headers_for(&self.refresh(&stale).await?))
+    }
+
+    async fn list_page(
+        &self,
+        session: &Session,
+        folder: &str,
+        query: &Query,
+        start: usize,
+    ) -> Result<Listing> {
+        let mut params: Vec<(String, String)> = [
+            ("sEcho", (start / self.page_size + 1).to_string()),
+            ("iColumns", "5".to_string()),
+            ("sColumns", ",,,,".to_string()),
+            ("iDisplayStart", start.to_string()),
+            ("iDisplayLength", self.page_size.to_string()),
+            ("sSearch", query.search.clone()),
+            ("bRegex", "false".to_string()),
+            ("iSortingCols", "0".to_string()),
+            ("cd", folder.to_string()),
+            ("csrftoken", session.csrftoken.clone()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+        for (i, (column, sortable)) in [
+            ("fname", true),
+            ("typeLabel", false),
+            ("size", true),
+            ("ftime", true),
+            ("", false),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            params.extend([
+                (format!("mDataProp_{i}"), column.to_string()),
+                (format!("sSearch_{i}"), query.columns[i].clone()),
+                (format!("bRegex_{i}"), "false".to_string()),
+                (format!("bSearchable_{i}"), "true".to_string()),
+                (format!("bSortable_{i}"), sortable.to_string()),
+            ]);
+        }
+
+        let url = format!("{BASE_URL}/file/json/dir");
+        let request = self
+            .client
+            .post(http_fixture::url(&url))
+            .header(header::COOKIE, &session.cookie)
+            .form(&params);
+        let resp = self.send(request, &url).await?;
+        if is_login_page(resp.url())
+            || matches!(
+                resp.status(),
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+            )
+        {
+            bail!("Session of {} expired", self.username);
+        }
+        Ok(serde_json::from_str(&resp.text().await?)?)
+    }
+
+    // Lists a folder, e.g. "/", page by page. The session is refreshed once if needed.
+    async fn list(&self, folder: &str, query: &Query) -> Result<Vec<Entry>> {
+        let mut session = self.session().await?;
+        let mut refreshed = false;
+        let mut entries = Vec::new();
+        loop {
+            let listing = match self.list_page(&session, folder, query, entries.len()).await {
+                Ok(listing) => listing,
+                Err(e) if !refreshed => {
+                    debug!("Listing {folder} failed: {e}");
+                    session = self.refresh(&session).await?;
+                    refreshed = true;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let done = listing.aa_data.is_empty();
+            entries.extend(listing.aa_data);
+            if done || entries.len() >= listing.i_total_display_records {
+                return Ok(entries);
+            }
+        }
+    }
+
+    // The paths of the files matching the search, relative to the root folder. The search
+    // would also skip the folders not named after it, so folders are listed by a query of
+    // their own, and every folder is walked.
+    pub async fn list_files(&self, search: &Search) -> Result<Vec<String>> {
+        let files_query = Query::files(search);
+        let mut files = Vec::new();
+        let mut folders = vec![String::new()];
+        while let Some(folder) = folders.pop() {
+            let cd = format!("/{folder}");
+            let mut entries = self.list(&cd, &files_query).await?;
+            if !files_query.is_empty() {
+                entries.retain(|entry| !entry.is_folder());
+                entries.extend(self.list(&cd, &Query::folders()).await?);
+            }
+            for entry in entries {
+                let path = match folder.is_empty() {
+                    true => entry.fname.clone(),
+                    false => format!("{folder}/{}", entry.fname),
+                };
+                match entry.is_folder() {
+                    true => folders.push(path),
+                    false => files.push(path),
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    pub fn download_url(path: &str) -> String {
+        format!("{BASE_URL}/file/d/{path}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+    use crate::parallel_download::{parallel_download, Download};
+    use tokio::sync::Semaphore;
+
+    #[tokio::test]
+    async fn test_list_files() {
+        http_fixture::replay_for_tests();
+        let client = PublishedPricesClient::new("folders", "")
+            .unwrap()
+            .with_page_size(2);
+        let search = Search {
+            pattern: Some("PriceFull".to_string()),
+            date: NaiveDate::from_ymd_opt(2023, 6, 11),
+        };
+        // Only requests searching the files by type and date, and the folders by type, are
+        // answered with these files.
+        let mut files = client.list_files(&search).await.unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "PriceFull7290873255550-001-202306110500.gz",
+                "PriceFull7290873255550-002-202306110500.gz",
+                "PriceFull7290873255550-004-202306110500.gz",
+                "archive/PriceFull7290873255550-003-202306110500.gz",
+            ]
+        );
+        assert!(is_login_page(
+            &Url::parse("https://url.publishedprices.co.il/login").unwrap()
+        ));
+        assert!(!is_login_page(
+            &Url::parse("https://url.publishedprices.co.il/file/d/login.gz").unwrap()
+        ));
+    }
+
+    // The first download of the file is redirected to the login page.
+    #[tokio::test]
+    async fn test_download_after_session_expired() {
+        http_fixture::replay_for_tests();
+        let client = Arc::new(PublishedPricesClient::new("expired", "").unwrap());
+        let dest = std::env::temp_dir()
+            .join("test_download_after_session_expired")
+            .join("Price7290873255550-001-202306110600.xml");
+        let _ = std::fs::remove_file(&dest);
+        let download = Download {
+            store: "expired".to_string(),
+            path: PublishedPricesClient::download_url("Price7290873255550-001-202306110600.xml"),
+            headers: Some(client.headers().await.unwrap()),
+            dest: dest.display().to_string(),
+            session: Some(client.clone()),
+        };
+        let stats = parallel_download(
+            vec![download],
+            Arc::new(Semaphore::new(1)),
+            Arc::new(Mutex::new(Manifest::default())),
+        )
+        .await;
+        assert_eq!(stats.downloaded, 1);
+        assert!(stats.failures.is_empty());
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "<Root/>");
+    }
+}
@@ -0,0 +1,122 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+// How fast requests can be sent to a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    pub max_concurrency: usize,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            requests_per_second: 10.0,
+            max_concurrency: 10,
+        }
+    }
+}
+
+// A token bucket holding at most one token, so that requests are evenly spaced.
+struct HostLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+    semaphore: Arc<Semaphore>,
+}
+
+impl HostLimiter {
+    fn new(limit: RateLimit) -> HostLimiter {
+        HostLimiter {
+            interval: Duration::from_secs_f64(1.0 / limit.requests_per_second.max(0.001)),
+            next: Mutex::new(Instant::now()),
+            semaphore: Arc::new(Semaphore::new(limit.max_concurrency.max(1))),
+        }
+    }
+
+    async fn acquire(&self) -> OwnedSemaphorePermit {
+        let permit = self.semaphore.clone().acquire_owned().await.unwrap();
+        // Each request reserves the next slot, and waits for it.
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let slot = (*next).max(now);
+            *next = slot + self.interval;
+            slot - now
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        permit
+    }
+}
+
+lazy_static! {
+    static ref LIMITERS: Mutex<HashMap<String, Arc<HostLimiter>>> = Mutex::new(HashMap::new());
+}
+
+pub fn host(url: &str) -> Option<String> {
+    reqwest::Url::parse(url)
+        .ok()?
+        .host_str()
+        .map(|host| host.to_string())
+}
+
+// Sets the rate limit of a host. Hosts that aren't configured use the default limit.
+pub fn configure(host: &str, limit: RateLimit) {
+    debug!("Rate limit for {host}: {limit:?}");
+    LIMITERS
+        .lock()
+        .unwrap()
+        .insert(host.to_string(), Arc::new(HostLimiter::new(limit)));
+}
+
+// Waits until a request can be sent to the host of the url. The request should be sent while the
+// returned permit is held.
+pub async fn acquire(url: &str) -> Option<OwnedSemaphorePermit> {
+    let host = host(url)?;
+    let limiter = LIMITERS
+        .lock()
+        .unwrap()
+        .entry(host)
+        .or_insert_with(|| Arc::new(HostLimiter::new(RateLimit::default())))
+        .clone();
+    Some(limiter.acquire().await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        configure(
+            "rate-limit.test",
+            RateLimit {
+                requests_per_second: 20.0,
+                max_concurrency: 2,
+            },
+        );
+        let start = Instant::now();
+        for _ in 0..5 {
+            assert!(acquire("http://rate-limit.test/file").await.is_some());
+        }
+        // The first request is immediate, the 4 next ones wait 50ms each.
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        let first = acquire("http://rate-limit.test/a").await;
+        let second = acquire("http://rate-limit.test/b").await;
+        let third = tokio::time::timeout(
+            Duration::from_millis(200),
+            acquire("http://rate-limit.test/c"),
+        );
+        assert!(third.await.is_err());
+        drop((first, second));
+
+        assert!(acquire("not a url").await.is_none());
+    }
+}
@@ -1,39 +1,180 @@
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
 
-use reqwest::Client;
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::Serialize;
 use tokio::sync::Semaphore;
 
-pub async fn post_to_text_with_retries(
-    client: &Client,
+use crate::rate_limit;
+use tracing::{debug, warn};
+
+// How requests are retried: only transient failures (5xx, 429, timeouts, connection errors and
+// responses cut short) are retried, with an exponential backoff. Other failures are returned right away.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 6,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // The delay before retrying after the given attempt (starting from 1), between half and all
+    // of the exponential backoff so that concurrent downloads don't retry at the same time.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        let half = delay / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+// A request that failed permanently, either because the failure isn't transient or because all
+// attempts failed.
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestFailure {
+    pub url: String,
+    pub attempts: u32,
+    pub status: Option<u16>,
+    pub error: String,
+}
+
+impl std::fmt::Display for RequestFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} failed after {} attempts: {}",
+            self.url, self.attempts, self.error
+        )
+    }
+}
+
+impl std::error::Error for RequestFailure {}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+// Body and decode errors usually mean the connection dropped while reading the response.
+fn is_retryable_error(e: &reqwest::Error) -> bool {
+    e.is_timeout()
+        || e.is_connect()
+        || e.is_body()
+        || e.is_decode()
+        || e.status().is_some_and(is_retryable_status)
+}
+
+// Retry-After is either a number of seconds or an HTTP date.
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+// Sends the request built by `request` and reads its response with `read`, retrying transient
+// failures according to `policy`. Each attempt is rate limited by host, and holds a permit of the
+// semaphore.
+pub async fn send_with_retries<T, Fut>(
+    policy: &RetryPolicy,
     url: &str,
-    body: String,
-    download_semaphore: Option<Arc<Semaphore>>,
-) -> Option<String> {
-    for _ in 0..10 {
-        let _permit = match download_semaphore.as_ref() {
-            Some(download_semaphore) => {
-                Some(download_semaphore.clone().acquire_owned().await.unwrap())
+    request: impl Fn() -> RequestBuilder,
+    read: impl Fn(Response) -> Fut,
+    semaphore: Option<&Arc<Semaphore>>,
+) -> Result<T, RequestFailure>
+where
+    Fut: Future<Output = reqwest::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let permit = match semaphore {
+            Some(semaphore) => {
+                Some(
+                    semaphore
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .map_err(|e| RequestFailure {
+                            url: url.to_string(),
+                            attempts: attempt,
+                            status: None,
+                            error: e.to_string(),
+                        })?,
+                )
             }
             None => None,
         };
-
-        match client
-            .post(url)
-            .header("content-type", "application/json;charset=UTF-8")
-            .body(body.clone())
-            .send()
-            .await
-        {
-            Ok(resp) => match resp.text().await {
-                Ok(text) => {
-                    return Some(text);
-                }
-                Err(_) => continue,
+        let host_permit = rate_limit::acquire(url).await;
+        let (status, error, retryable, retry_after) = match request().send().await {
+            Ok(resp) if resp.status().is_success() => match read(resp).await {
+                Ok(value) => return Ok(value),
+                Err(e) => (None, e.to_string(), is_retryable_error(&e), None),
             },
-            Err(_) => continue,
+            Ok(resp) => (
+                Some(resp.status()),
+                format!("HTTP status {}", resp.status()),
+                is_retryable_status(resp.status()),
+                parse_retry_after(resp.headers()),
+            ),
+            Err(e) => (e.status(), e.to_string(), is_retryable_error(&e), None),
         };
+        drop((permit, host_permit));
+        if !retryable || attempt >= policy.max_attempts {
+            warn!("Giving up on {url} after {attempt} attempts: {error}");
+            return Err(RequestFailure {
+                url: url.to_string(),
+                attempts: attempt,
+                status: status.map(|s| s.as_u16()),
+                error,
+            });
+        }
+        let delay = retry_after
+            .map(|d| d.min(policy.max_delay))
+            .unwrap_or_else(|| policy.backoff(attempt));
+        debug!("Retrying {url} in {delay:?} after: {error}");
+        tokio::time::sleep(delay).await;
     }
-    None
+}
+
+pub async fn post_to_text_with_retries(
+    client: &Client,
+    url: &str,
+    body: String,
+    download_semaphore: Option<Arc<Semaphore>>,
+) -> Option<String> {
+    send_with_retries(
+        &RetryPolicy::default(),
+        url,
+        || {
+            client
+                .post(url)
+                .header("content-type", "application/json;charset=UTF-8")
+                .body(body.clone())
+        },
+        Response::text,
+        download_semaphore.as_ref(),
+    )
+    .await
+    .ok()
 }
 
 pub async fn post_to_text_with_headers_with_retries(
@@ -43,32 +184,21 @@ pub async fn post_to_text_with_headers_with_retries(
     download_semaphore: Option<Arc<Semaphore>>,
     header_map: reqwest::header::HeaderMap,
 ) -> Option<String> {
-    for _ in 0..10 {
-        let _permit = match download_semaphore.as_ref() {
-            Some(download_semaphore) => {
-                Some(download_semaphore.clone().acquire_owned().await.unwrap())
-            }
-            None => None,
-        };
-
-        match client
-            .post(url)
-            .headers(header_map.clone())
-            .header("content-type", "application/json;charset=UTF-8")
-            .body(body.clone())
-            .send()
-            .await
-        {
-            Ok(resp) => match resp.text().await {
-                Ok(text) => {
-                    return Some(text);
-                }
-                Err(_) => continue,
-            },
-            Err(_) => continue,
-        };
-    }
-    None
+    send_with_retries(
+        &RetryPolicy::default(),
+        url,
+        || {
+            client
+                .post(url)
+                .headers(header_map.clone())
+                .header("content-type", "application/json;charset=UTF-8")
+                .body(body.clone())
+        },
+        Response::text,
+        download_semaphore.as_ref(),
+    )
+    .await
+    .ok()
 }
 
 pub async fn get_to_text_with_retries(url: &str) -> Option<String> {
@@ -76,38 +206,121 @@ pub async fn get_to_text_with_retries(url: &str) -> Option<String> {
         .timeout(std::time::Duration::from_secs(60))
         .build()
         .unwrap();
-    for _ in 0..10 {
-        match client.get(url).send().await {
-            Ok(resp) => match resp.text().await {
-                Ok(text) => {
-                    return Some(text);
-                }
-                Err(_) => continue,
-            },
-            Err(_) => continue,
-        };
-    }
-    None
+    send_with_retries(
+        &RetryPolicy::default(),
+        url,
+        || client.get(url),
+        Response::text,
+        None,
+    )
+    .await
+    .ok()
 }
 
 pub async fn get_json_to_text_with_retries(url: &str) -> Option<String> {
     let client = reqwest::Client::new();
 
-    for _ in 0..10 {
-        match client
-            .get(url)
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .send()
-            .await
-        {
-            Ok(resp) => match resp.text().await {
-                Ok(text) => {
-                    return Some(text);
-                }
-                Err(_) => continue,
-            },
-            Err(_) => continue,
+    send_with_retries(
+        &RetryPolicy::default(),
+        url,
+        || {
+            client
+                .get(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+        },
+        Response::text,
+        None,
+    )
+    .await
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
         };
+        for (attempt, max) in [(1, 1), (2, 2), (3, 4), (4, 8), (5, 10), (9, 10)] {
+            let delay = policy.backoff(attempt);
+            let max = Duration::from_secs(max);
+            assert!(delay >= max / 2 && delay <= max, "{attempt}: {delay:?}");
+        }
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(120)));
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(parse_retry_after(&headers), None);
+        let in_a_minute = (chrono::Utc::now() + chrono::Duration::seconds(61)).to_rfc2822();
+        headers.insert(RETRY_AFTER, in_a_minute.parse().unwrap());
+        let delay = parse_retry_after(&headers).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(61));
+    }
+
+    #[tokio::test]
+    async fn test_send_with_retries() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        };
+        let client = Client::new();
+        let send = |url: String| {
+            let (policy, client) = (&policy, &client);
+            async move {
+                send_with_retries(policy, &url, || client.get(&url), Response::text, None).await
+            }
+        };
+
+        // Nothing listens on the port, so every attempt fails to connect.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let failure = send(closed).await.unwrap_err();
+        assert_eq!((failure.attempts, failure.status), (3, None));
+
+        // The first responses are cut short, then one is complete.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            for body in ["ab", "ab", "abcd"] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = [0; 1024];
+                let _ = socket.read(&mut request).await.unwrap();
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n{body}");
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        assert_eq!(send(url.clone()).await.unwrap(), "abcd");
+        server.await.unwrap();
+
+        // Client errors aren't retried.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            let response = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+        let failure = send(url).await.unwrap_err();
+        assert_eq!((failure.attempts, failure.status), (1, Some(404)));
+        server.await.unwrap();
     }
-    None
 }
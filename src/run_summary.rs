@@ -0,0 +1,136 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::reqwest_utils::RequestFailure;
+
+pub const RUN_SUMMARY_PATH: &str = "download_summary.json";
+
+// What was downloaded for a single store.
+#[derive(Debug, Default, Serialize)]
+pub struct StoreSummary {
+    pub store: String,
+    pub listed: usize,
+    pub downloaded: usize,
+    // Files that a previous run already downloaded.
+    pub skipped: usize,
+    pub failed: usize,
+    pub bytes: u64,
+    pub duration_secs: f64,
+    // Set when the files of the store couldn't be listed.
+    pub error: Option<String>,
+    pub failures: Vec<RequestFailure>,
+}
+
+impl StoreSummary {
+    // The files listed that are on disk after the run, or would be in quick mode.
+    pub fn available(&self) -> usize {
+        self.listed.saturating_sub(self.failed)
+    }
+}
+
+// The outcome of downloading the files of all the stores.
+#[derive(Debug, Default, Serialize)]
+pub struct RunSummary {
+    pub stores: Vec<StoreSummary>,
+}
+
+impl RunSummary {
+    pub fn failures(&self) -> impl Iterator<Item = &RequestFailure> {
+        self.stores.iter().flat_map(|s| s.failures.iter())
+    }
+
+    // The stores whose files couldn't be listed, or that have fewer files than expected.
+    pub fn shortfalls(&self, expected: &HashMap<String, usize>) -> Vec<&StoreSummary> {
+        self.stores
+            .iter()
+            .filter(|s| {
+                s.error.is_some() || s.available() < expected.get(&s.store).copied().unwrap_or(0)
+            })
+            .collect()
+    }
+
+    pub fn table(&self) -> String {
+        let mut table = format!(
+            "{:<22}{:>8}{:>12}{:>9}{:>8}{:>10}{:>10}  error\n",
+            "store", "listed", "downloaded", "skipped", "failed", "MB", "seconds"
+        );
+        for s in &self.stores {
+            writeln!(
+                table,
+                "{:<22}{:>8}{:>12}{:>9}{:>8}{:>10.1}{:>10.1}  {}",
+                s.store,
+                s.listed,
+                s.downloaded,
+                s.skipped,
+                s.failed,
+                s.bytes as f64 / 1e6,
+                s.duration_secs,
+                s.error.as_deref().unwrap_or("")
+            )
+            .unwrap();
+        }
+        table
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shortfalls() {
+        let summary = RunSummary {
+            stores: vec![
+                StoreSummary {
+                    store: "shufersal".to_string(),
+                    error: Some("Cannot find link to Shufersal last page".to_string()),
+                    ..Default::default()
+                },
+                StoreSummary {
+                    store: "victory".to_string(),
+                    listed: 10,
+                    downloaded: 6,
+                    skipped: 1,
+                    failed: 3,
+                    bytes: 2_500_000,
+                    ..Default::default()
+                },
+                StoreSummary {
+                    store: "mega".to_string(),
+                    listed: 4,
+                    downloaded: 4,
+                    ..Default::default()
+                },
+            ],
+        };
+        let names = |expected: &[(&str, usize)]| {
+            let expected = expected
+                .iter()
+                .map(|(store, count)| (store.to_string(), *count))
+                .collect();
+            summary
+                .shortfalls(&expected)
+                .iter()
+                .map(|s| s.store.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&[]), vec!["shufersal"]);
+        assert_eq!(names(&[("victory", 7), ("mega", 4)]), vec!["shufersal"]);
+        assert_eq!(
+            names(&[("victory", 8), ("mega", 5)]),
+            vec!["shufersal", "victory", "mega"]
+        );
+
+        let table = summary.table();
+        assert_eq!(table.lines().count(), 4);
+        assert!(table.lines().nth(2).unwrap().starts_with("victory"));
+        assert!(table.contains("2.5"));
+    }
+}
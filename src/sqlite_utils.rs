@@ -5,15 +5,59 @@ use rusqlite::{params, Connection};
 use tracing::info;
 
 use crate::models::{
-    Barcode, Chain, ItemInfo, ItemKey, RamiLevyMetadata, ShufersalMetadata, VictoryMetadata,
-    YochananofMetadata,
+    Barcode, Chain, ItemInfo, ItemKey, ItemPrice, Promotions, RamiLevyMetadata, ShufersalMetadata,
+    VictoryMetadata, YochananofMetadata,
 };
+use crate::product_matching;
 
 fn connection() -> Result<Connection> {
     let path = "data.sqlite";
     Ok(rusqlite::Connection::open(path)?)
 }
 
+// With --stream-prices, the prices of each file are written as soon as the file is read, and
+// moved to the Prices table when it is saved.
+pub fn create_streamed_prices() -> Result<Connection> {
+    let connection = connection()?;
+    connection.execute("DROP TABLE IF EXISTS StreamedPrices", ())?;
+    connection.execute(
+        "CREATE TABLE StreamedPrices (
+                        ChainId int NOT NULL,
+                        StoreId int NOT NULL,
+                        ItemCode TEXT,
+                        ItemPrice INTEGER,
+                        UnitOfMeasurePrice INTEGER,
+                        NormalizedPrice INTEGER,
+                        PRIMARY KEY(ChainId, StoreId, ItemCode)) ",
+        (),
+    )?;
+    Ok(connection)
+}
+
+// Files may list an item twice, only its first price is kept.
+pub fn save_streamed_prices(
+    connection: &mut Connection,
+    prices: &[(Barcode, ItemPrice)],
+) -> Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction
+            .prepare("INSERT OR IGNORE INTO StreamedPrices (ChainID, StoreId, ItemCode, ItemPrice, UnitOfMeasurePrice, NormalizedPrice) VALUES (?1,?2,?3,?4,?5,?6)")?;
+        for (item_code, price) in prices {
+            statement.execute(params![
+                price.chain_id,
+                price.store_id,
+                item_code,
+                price.price,
+                price.unit_of_measure_price,
+                price.normalized_price
+            ])?;
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
 pub fn save_shufersal_metadata_to_sqlite(
     shufersal_metadata: &HashMap<Barcode, ShufersalMetadata>,
 ) -> Result<()> {
@@ -99,6 +143,7 @@ pub fn save_rami_levy_metadata_to_sqlite(
 pub fn save_to_sqlite(
     chains: &Vec<Chain>,
     item_infos: &HashMap<ItemKey, ItemInfo>,
+    promotions: &[Promotions],
     save_to_sqlite_only: &str,
 ) -> Result<()> {
     let mut connection = connection()?;
@@ -151,12 +196,15 @@ pub fn save_to_sqlite(
                         StoreName TEXT,
                         Address TEXT,
                         City TEXT,
+                        CityId int,
                         ZipCode TEXT,
+                        Latitude REAL,
+                        Longitude REAL,
                         PRIMARY KEY(ChainId,SubChainId,StoreId)) ",
             (),
         )?;
         let mut statement = connection
-            .prepare("INSERT INTO Stores (ChainId,SubchainId, StoreId, StoreType, StoreName, Address, City, ZipCode) VALUES (?1,?2,?3,?4,?5,?6,?7,?8)")?;
+            .prepare("INSERT INTO Stores (ChainId,SubchainId, StoreId, StoreType, StoreName, Address, City, CityId, ZipCode, Latitude, Longitude) VALUES (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11)")?;
         for chain in chains {
             for subchain in &chain.subchains {
                 for store in &subchain.stores {
@@ -168,7 +216,10 @@ pub fn save_to_sqlite(
                         store.store_name,
                         store.address,
                         store.city,
+                        store.city_id,
                         store.zip_code,
+                        store.latitude,
+                        store.longitude,
                     ])?;
                 }
             }
@@ -189,9 +240,20 @@ pub fn save_to_sqlite(
                         UnitOfMeasure TEXT,
                         IsWeighted TEXT,
                         QuantityInPackage TEXT,
+                        NormalizedQuantity REAL,
+                        NormalizedUnit TEXT,
+                        Policies TEXT,
                         PRIMARY KEY(ChainId, ItemCode)) ",
             (),
         )?;
+        connection.execute(
+            "CREATE TABLE ItemAlternativeNames (
+                        ChainId int,
+                        ItemCode int NOT NULL,
+                        ItemName TEXT NOT NULL,
+                        PRIMARY KEY(ChainId, ItemCode, ItemName)) ",
+            (),
+        )?;
         let transaction = connection.transaction()?;
         {
             let tx = &transaction;
@@ -207,7 +269,13 @@ pub fn save_to_sqlite(
                     Quantity,
                     UnitOfMeasure,
                     IsWeighted,
-                    QuantityInPackage) VALUES (?,?,?,?,?,?,?,?,?,?,?)",
+                    QuantityInPackage,
+                    NormalizedQuantity,
+                    NormalizedUnit,
+                    Policies) VALUES (?,?,?,?,?,?,?,?,?,?,?,?,?,?)",
+            )?;
+            let mut name_statement = tx.prepare(
+                "INSERT INTO ItemAlternativeNames (ChainId, ItemCode, ItemName) VALUES (?1,?2,?3)",
             )?;
             for (item_key, item_info) in item_infos {
                 statement
@@ -222,9 +290,52 @@ pub fn save_to_sqlite(
                         item_info.quantity,
                         item_info.unit_of_measure,
                         item_info.b_is_weighted,
-                        item_info.qty_in_package
+                        item_info.qty_in_package,
+                        item_info.normalized_quantity.map(|q| q.amount),
+                        item_info.normalized_quantity.map(|q| q.unit.as_str()),
+                        // As given to --canonical-policy, e.g. "item_name=weighted,quantity=longest".
+                        item_info
+                            .policies
+                            .iter()
+                            .map(|(field, policy)| format!("{field}={policy}"))
+                            .collect::<Vec<_>>()
+                            .join(","),
                     ])
                     .with_context(|| format!("With item_key = {:?}", item_key))?;
+                for name in &item_info.alternative_names {
+                    name_statement.execute(params![item_key.chain_id, item_key.item_code, name])?;
+                }
+            }
+        }
+        transaction.commit()?;
+    }
+    if save_to_sqlite_only.is_empty() || save_to_sqlite_only.eq_ignore_ascii_case("productgroups") {
+        info!("Saving table ProductGroups to sqlite");
+        connection.execute(
+            "CREATE TABLE ProductGroups (
+                        ChainId int,
+                        ItemCode int NOT NULL,
+                        GroupChainId int,
+                        GroupItemCode int NOT NULL,
+                        Confidence REAL,
+                        PRIMARY KEY(ChainId, ItemCode)) ",
+            (),
+        )?;
+        let product_groups = product_matching::group_products(item_infos);
+        info!("Grouped {} items into products", product_groups.len());
+        let transaction = connection.transaction()?;
+        {
+            let mut statement = transaction.prepare(
+                "INSERT INTO ProductGroups (ChainId, ItemCode, GroupChainId, GroupItemCode, Confidence) VALUES (?1,?2,?3,?4,?5)",
+            )?;
+            for (item_key, product_group) in &product_groups {
+                statement.execute(params![
+                    item_key.chain_id,
+                    item_key.item_code,
+                    product_group.group.chain_id,
+                    product_group.group.item_code,
+                    product_group.confidence,
+                ])?;
             }
         }
         transaction.commit()?;
@@ -236,8 +347,9 @@ pub fn save_to_sqlite(
                         ChainId int NOT NULL,
                         StoreId int NOT NULL,
                         ItemCode TEXT,
-                        ItemPrice TEXT,
-                        UnitOfMeasurePrice TEXT,
+                        ItemPrice INTEGER,
+                        UnitOfMeasurePrice INTEGER,
+                        NormalizedPrice INTEGER,
                         PRIMARY KEY(ChainId, StoreId, ItemCode)) ",
             (),
         )?;
@@ -245,7 +357,7 @@ pub fn save_to_sqlite(
         {
             let tx = &transaction;
             let mut statement = tx
-            .prepare("INSERT INTO Prices (ChainID, StoreId, ItemCode, ItemPrice, UnitOfMeasurePrice) VALUES (?1,?2,?3,?4,?5)")?;
+            .prepare("INSERT INTO Prices (ChainID, StoreId, ItemCode, ItemPrice, UnitOfMeasurePrice, NormalizedPrice) VALUES (?1,?2,?3,?4,?5,?6)")?;
             for (item_key, item_info) in item_infos {
                 for price in &item_info.prices {
                     statement
@@ -254,7 +366,8 @@ pub fn save_to_sqlite(
                             price.store_id,
                             item_key.item_code,
                             price.price,
-                            price.unit_of_measure_price
+                            price.unit_of_measure_price,
+                            price.normalized_price
                         ])
                         .with_context(|| {
                             format!("With item_key = {:?}, price = {:?}", item_key, price)
@@ -263,6 +376,116 @@ pub fn save_to_sqlite(
             }
         }
         transaction.commit()?;
+        let streamed: bool = connection.query_row(
+            "SELECT count(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'StreamedPrices'",
+            (),
+            |row| row.get(0),
+        )?;
+        if streamed {
+            info!("Moving the streamed prices to the table Prices");
+            connection.execute(
+                "INSERT OR IGNORE INTO Prices SELECT ChainId, StoreId, ItemCode, ItemPrice, UnitOfMeasurePrice, NormalizedPrice FROM StreamedPrices",
+                (),
+            )?;
+            connection.execute("DROP TABLE StreamedPrices", ())?;
+        }
+    }
+    if save_to_sqlite_only.is_empty() || save_to_sqlite_only.eq_ignore_ascii_case("promotions") {
+        info!("Saving tables Promotions and PromotionItems to sqlite");
+        connection.execute(
+            "CREATE TABLE Promotions (
+                        ChainId int NOT NULL,
+                        SubchainId int NOT NULL,
+                        StoreId int NOT NULL,
+                        PromotionId TEXT NOT NULL,
+                        Description TEXT,
+                        StartDate TEXT,
+                        EndDate TEXT,
+                        DiscountedPrice INTEGER,
+                        DiscountRate REAL,
+                        MinQty REAL,
+                        MaxQty REAL,
+                        ClubOnly int,
+                        PRIMARY KEY(ChainId, SubchainId, StoreId, PromotionId)) ",
+            (),
+        )?;
+        connection.execute(
+            "CREATE TABLE PromotionItems (
+                        ChainId int NOT NULL,
+                        SubchainId int NOT NULL,
+                        StoreId int NOT NULL,
+                        PromotionId TEXT NOT NULL,
+                        ItemCode int NOT NULL,
+                        IsGift int NOT NULL,
+                        PRIMARY KEY(ChainId, SubchainId, StoreId, PromotionId, ItemCode, IsGift)) ",
+            (),
+        )?;
+        let transaction = connection.transaction()?;
+        {
+            let tx = &transaction;
+            let mut promotion_statement = tx.prepare(
+                "INSERT INTO Promotions (
+                    ChainId,
+                    SubchainId,
+                    StoreId,
+                    PromotionId,
+                    Description,
+                    StartDate,
+                    EndDate,
+                    DiscountedPrice,
+                    DiscountRate,
+                    MinQty,
+                    MaxQty,
+                    ClubOnly) VALUES (?,?,?,?,?,?,?,?,?,?,?,?)",
+            )?;
+            let mut item_statement = tx.prepare(
+                "INSERT INTO PromotionItems (ChainId, SubchainId, StoreId, PromotionId, ItemCode, IsGift) VALUES (?1,?2,?3,?4,?5,?6)",
+            )?;
+            let format_date = |date: &Option<chrono::NaiveDateTime>| {
+                date.map(|d| d.format("%Y-%m-%d %H:%M:%S").to_string())
+            };
+            for store_promotions in promotions {
+                for promotion in &store_promotions.promotions {
+                    promotion_statement
+                        .execute(params![
+                            store_promotions.chain_id,
+                            store_promotions.subchain_id,
+                            store_promotions.store_id,
+                            promotion.promotion_id,
+                            promotion.description,
+                            format_date(&promotion.start),
+                            format_date(&promotion.end),
+                            promotion.discounted_price,
+                            promotion.discount_rate,
+                            promotion.min_qty,
+                            promotion.max_qty,
+                            promotion.club_only,
+                        ])
+                        .with_context(|| {
+                            format!(
+                                "With chain_id = {}, store_id = {}, promotion = {:?}",
+                                store_promotions.chain_id, store_promotions.store_id, promotion
+                            )
+                        })?;
+                    let items = promotion
+                        .item_codes
+                        .iter()
+                        .map(|code| (code, false))
+                        .chain(promotion.gift_items.iter().map(|code| (code, true)));
+                    for (item_code, is_gift) in items {
+                        item_statement.execute(params![
+                            store_promotions.chain_id,
+                            store_promotions.subchain_id,
+                            store_promotions.store_id,
+                            promotion.promotion_id,
+                            item_code,
+                            is_gift,
+                        ])?;
+                    }
+                }
+            }
+        }
+        transaction.commit()?;
     }
     Ok(())
 }
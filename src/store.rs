@@ -1,119 +1,319 @@
-#[derive(Clone)]
+use crate::rate_limit::{self, RateLimit};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+// A secret that is either written in the config, or read from an environment variable.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Credential {
+    Plain(String),
+    Env { env: String },
+}
+
+impl Credential {
+    pub fn resolve(&self) -> Result<String> {
+        match self {
+            Credential::Plain(value) => Ok(value.clone()),
+            Credential::Env { env } => {
+                std::env::var(env).with_context(|| format!("Cannot read credential from ${env}"))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
 pub enum Website {
-    // param: username
-    PublishedPrice(&'static str),
-    // param: username, password
-    PublishedPriceWithPassword(&'static str, &'static str),
+    PublishedPrice {
+        username: String,
+    },
+    PublishedPriceWithPassword {
+        username: String,
+        password: Credential,
+    },
     Shufersal,
     // These are different urls that work the same and use the same code.
     // Interistingly, the download prefix can be switched from one store to another
-    // params: initial url, download prefix
-    SimpleJsonToGet(&'static str, &'static str),
+    SimpleJsonToGet {
+        initial_url: String,
+        download_prefix: String,
+    },
     // All MatrixCatalog stores use the same main page - filtering need to happen later.
     // Paramter: chain. currently: ויקטורי, מחסני השוק, ח. כהן, סופר ברקת
-    MatrixCatalog(&'static str),
-    // param: main page
-    PublishPrice(&'static str),
+    MatrixCatalog {
+        chain: String,
+    },
+    PublishPrice {
+        main_page: String,
+    },
     NetivHahesed,
     SuperPharm,
 }
 
-#[derive(Clone)]
+impl Website {
+    // The host most requests to the website are sent to.
+    pub fn host(&self) -> Option<String> {
+        match self {
+            Website::PublishedPrice { .. } | Website::PublishedPriceWithPassword { .. } => {
+                Some("url.publishedprices.co.il".to_string())
+            }
+            Website::Shufersal => Some("prices.shufersal.co.il".to_string()),
+            Website::SimpleJsonToGet {
+                initial_url: url, ..
+            }
+            | Website::PublishPrice { main_page: url } => rate_limit::host(url),
+            Website::MatrixCatalog { .. } => Some("matrixcatalog.co.il".to_string()),
+            Website::NetivHahesed => Some("141.226.222.202".to_string()),
+            Website::SuperPharm => Some("prices.super-pharm.co.il".to_string()),
+        }
+    }
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Store {
+    pub name: String,
     pub website: Website,
-    pub name: &'static str,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    // Overrides the default rate limit of the website host.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    // The run fails when fewer files are downloaded.
+    #[serde(default)]
+    pub expected_files: Option<usize>,
 }
 impl Store {
-    fn new(website: Website, name: &'static str) -> Store {
-        Store { website, name }
+    fn new(website: Website, name: &str) -> Store {
+        Store {
+            name: name.to_string(),
+            website,
+            enabled: true,
+            rate_limit: None,
+            expected_files: None,
+        }
+    }
+
+    fn with_rate_limit(self, rate_limit: RateLimit) -> Store {
+        Store {
+            rate_limit: Some(rate_limit),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoreRegistry {
+    stores: Vec<Store>,
+}
+
+// Reads the stores from a TOML or JSON file, which replaces the built-in list. Disabled stores are
+// skipped.
+pub fn load_store_configs(path: &str) -> Result<Vec<Store>> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("Cannot read {path}"))?;
+    let registry: StoreRegistry = match std::path::Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+    {
+        Some("toml") => toml::from_str(&content)?,
+        Some("json") => serde_json::from_str(&content)?,
+        _ => bail!("{path} should be a .toml or a .json file"),
+    };
+    for store in &registry.stores {
+        if registry
+            .stores
+            .iter()
+            .filter(|s| s.name == store.name)
+            .count()
+            > 1
+        {
+            return Err(anyhow!("Store {} is defined more than once", store.name));
+        }
     }
+    Ok(registry.stores.into_iter().filter(|s| s.enabled).collect())
 }
 
 pub fn get_store_configs() -> Vec<Store> {
     // As defined and ordered on https://www.gov.il/he/departments/legalInfo/cpfta_prices_regulations
     [
         Store::new(
-            Website::SimpleJsonToGet(
-                "https://www.kingstore.co.il/Food_Law/MainIO_Hok.aspx",
-                "https://www.kingstore.co.il/Food_Law/Download/",
-            ),
+            Website::SimpleJsonToGet {
+                initial_url: "https://www.kingstore.co.il/Food_Law/MainIO_Hok.aspx".into(),
+                download_prefix: "https://www.kingstore.co.il/Food_Law/Download/".into(),
+            },
             "king_store",
         ),
         Store::new(
-            Website::SimpleJsonToGet(
-                "http://maayan2000.binaprojects.com/MainIO_Hok.aspx",
-                "http://maayan2000.binaprojects.com/Download/",
-            ),
+            Website::SimpleJsonToGet {
+                initial_url: "http://maayan2000.binaprojects.com/MainIO_Hok.aspx".into(),
+                download_prefix: "http://maayan2000.binaprojects.com/Download/".into(),
+            },
             "maayan_2000",
         ),
         Store::new(
-            Website::SimpleJsonToGet(
-                "https://goodpharm.binaprojects.com/MainIO_Hok.aspx",
-                "https://goodpharm.binaprojects.com/Download/",
-            ),
+            Website::SimpleJsonToGet {
+                initial_url: "https://goodpharm.binaprojects.com/MainIO_Hok.aspx".into(),
+                download_prefix: "https://goodpharm.binaprojects.com/Download/".into(),
+            },
             "good_pharm",
         ),
-        Store::new(Website::PublishedPrice("doralon"), "dor_alon"),
-        Store::new(Website::MatrixCatalog("ויקטורי"), "victory"),
         Store::new(
-            Website::SimpleJsonToGet(
-                "http://zolvebegadol.binaprojects.com/MainIO_Hok.aspx",
-                "http://zolvebegadol.binaprojects.com/Download/",
-            ),
+            Website::PublishedPrice {
+                username: "doralon".into(),
+            },
+            "dor_alon",
+        ),
+        Store::new(
+            Website::MatrixCatalog {
+                chain: "ויקטורי".into(),
+            },
+            "victory",
+        ),
+        Store::new(
+            Website::SimpleJsonToGet {
+                initial_url: "http://zolvebegadol.binaprojects.com/MainIO_Hok.aspx".into(),
+                download_prefix: "http://zolvebegadol.binaprojects.com/Download/".into(),
+            },
             "zol_vebegadol",
         ),
-        Store::new(Website::MatrixCatalog("ח. כהן"), "h_cohen"),
-        Store::new(Website::PublishedPrice("TivTaam"), "tiv_taam"),
         Store::new(
-            Website::PublishPrice("http://publishprice.ybitan.co.il/"),
+            Website::MatrixCatalog {
+                chain: "ח. כהן".into(),
+            },
+            "h_cohen",
+        ),
+        Store::new(
+            Website::PublishedPrice {
+                username: "TivTaam".into(),
+            },
+            "tiv_taam",
+        ),
+        Store::new(
+            Website::PublishPrice {
+                main_page: "http://publishprice.ybitan.co.il/".into(),
+            },
             "yenot_bitan",
         ),
         Store::new(
-            Website::PublishPrice("http://publishprice.mega.co.il/"),
+            Website::PublishPrice {
+                main_page: "http://publishprice.mega.co.il/".into(),
+            },
             "mega",
         ),
         Store::new(
-            Website::PublishPrice("http://publishprice.mega-market.co.il/"),
+            Website::PublishPrice {
+                main_page: "http://publishprice.mega-market.co.il/".into(),
+            },
             "mega_market",
         ),
-        Store::new(Website::MatrixCatalog("מחסני השוק"), "mahsanei_hashuk"),
-        Store::new(Website::PublishedPrice("HaziHinam"), "hatzi_hinam"),
-        Store::new(Website::PublishedPrice("yohananof"), "yohananof"),
-        Store::new(Website::PublishedPrice("osherad"), "osher_ad"),
+        Store::new(
+            Website::MatrixCatalog {
+                chain: "מחסני השוק".into(),
+            },
+            "mahsanei_hashuk",
+        ),
+        Store::new(
+            Website::PublishedPrice {
+                username: "HaziHinam".into(),
+            },
+            "hatzi_hinam",
+        ),
+        Store::new(
+            Website::PublishedPrice {
+                username: "yohananof".into(),
+            },
+            "yohananof",
+        ),
+        Store::new(
+            Website::PublishedPrice {
+                username: "osherad".into(),
+            },
+            "osher_ad",
+        ),
         Store::new(Website::NetivHahesed, "netiv_hahesed"),
-        Store::new(Website::PublishedPrice("SalachD"), "salach_dabbah"),
-        Store::new(Website::SuperPharm, "superpharm"),
-        Store::new(Website::PublishedPrice("Stop_Market"), "stop_market"),
-        Store::new(Website::MatrixCatalog("סופר ברקת"), "super_bareket"),
-        Store::new(Website::PublishedPrice("politzer"), "politzer"),
         Store::new(
-            Website::PublishedPriceWithPassword("Paz_bo", "paz468"),
+            Website::PublishedPrice {
+                username: "SalachD".into(),
+            },
+            "salach_dabbah",
+        ),
+        // Every file needs an additional request to get its link.
+        Store::new(Website::SuperPharm, "superpharm").with_rate_limit(RateLimit {
+            requests_per_second: 5.0,
+            max_concurrency: 4,
+        }),
+        Store::new(
+            Website::PublishedPrice {
+                username: "Stop_Market".into(),
+            },
+            "stop_market",
+        ),
+        Store::new(
+            Website::MatrixCatalog {
+                chain: "סופר ברקת".into(),
+            },
+            "super_bareket",
+        ),
+        Store::new(
+            Website::PublishedPrice {
+                username: "politzer".into(),
+            },
+            "politzer",
+        ),
+        Store::new(
+            Website::PublishedPriceWithPassword {
+                username: "Paz_bo".into(),
+                password: Credential::Plain("paz468".into()),
+            },
             "paz",
         ),
         Store::new(
-            Website::SimpleJsonToGet(
-                "http://paz.binaprojects.com/MainIO_Hok.aspx",
-                "http://paz.binaprojects.com/Download/",
-            ),
+            Website::SimpleJsonToGet {
+                initial_url: "http://paz.binaprojects.com/MainIO_Hok.aspx".into(),
+                download_prefix: "http://paz.binaprojects.com/Download/".into(),
+            },
             "super_yoda",
         ),
-        Store::new(Website::PublishedPrice("freshmarket"), "fresh_market"),
-        Store::new(Website::PublishedPrice("Keshet"), "keshet"),
-        Store::new(Website::PublishedPrice("RamiLevi"), "rami_levy"),
-        Store::new(Website::PublishedPrice("SuperCofixApp"), "super_cofix"),
+        Store::new(
+            Website::PublishedPrice {
+                username: "freshmarket".into(),
+            },
+            "fresh_market",
+        ),
+        Store::new(
+            Website::PublishedPrice {
+                username: "Keshet".into(),
+            },
+            "keshet",
+        ),
+        Store::new(
+            Website::PublishedPrice {
+                username: "RamiLevi".into(),
+            },
+            "rami_levy",
+        ),
+        Store::new(
+            Website::PublishedPrice {
+                username: "SuperCofixApp".into(),
+            },
+            "super_cofix",
+        ),
         Store::new(Website::Shufersal, "shufersal"),
         Store::new(
-            Website::SimpleJsonToGet(
-                "http://shuk-hayir.binaprojects.com/MainIO_Hok.aspx",
-                "http://shuk-hayir.binaprojects.com/Download/",
-            ),
+            Website::SimpleJsonToGet {
+                initial_url: "http://shuk-hayir.binaprojects.com/MainIO_Hok.aspx".into(),
+                download_prefix: "http://shuk-hayir.binaprojects.com/Download/".into(),
+            },
             "shuk_hayir", // AKA ShukCity
         ),
         Store::new(
-            Website::SimpleJsonToGet(
-                "http://shefabirkathashem.binaprojects.com/MainIO_Hok.aspx",
-                "http://shefabirkathashem.binaprojects.com/Download/",
-            ),
+            Website::SimpleJsonToGet {
+                initial_url: "http://shefabirkathashem.binaprojects.com/MainIO_Hok.aspx".into(),
+                download_prefix: "http://shefabirkathashem.binaprojects.com/Download/".into(),
+            },
             "shefa_birkat_hashem",
         ),
     ]
@@ -123,11 +323,11 @@ pub fn get_store_configs() -> Vec<Store> {
 const MINIMAL_STORE_CONFIGS: &'static [&'static str] =
     &["Yohananof", "Shufersal", "Super_Yoda", "Victory"];
 
-pub fn get_minimal_store_configs() -> Vec<Store> {
+pub fn get_minimal_store_configs(stores: Vec<Store>) -> Vec<Store> {
     let mut results = Vec::new();
 
-    for store in get_store_configs() {
-        if MINIMAL_STORE_CONFIGS.contains(&store.name) {
+    for store in stores {
+        if MINIMAL_STORE_CONFIGS.contains(&store.name.as_str()) {
             results.push(store);
         }
     }
@@ -135,15 +335,15 @@ pub fn get_minimal_store_configs() -> Vec<Store> {
     return results;
 }
 
-pub fn get_debug_store_configs() -> Vec<Store> {
-    return get_store_configs()
+pub fn get_debug_store_configs(stores: Vec<Store>) -> Vec<Store> {
+    stores
         .into_iter()
         .filter(|s| s.name == "superpharm")
-        .collect();
+        .collect()
 }
 
-pub fn get_store_config(name: &str) -> Option<Store> {
-    return get_store_configs().into_iter().find(|s| s.name == name);
+pub fn get_store_config(stores: Vec<Store>, name: &str) -> Option<Store> {
+    stores.into_iter().find(|s| s.name == name)
 }
 
 #[cfg(test)]
@@ -156,4 +356,48 @@ mod tests {
             assert!(!s.name.is_empty());
         }
     }
+
+    #[test]
+    fn test_load_store_configs() {
+        let path = std::env::temp_dir().join("test_stores.toml");
+        std::fs::write(
+            &path,
+            r#"
+[[stores]]
+name = "paz"
+website = { type = "PublishedPriceWithPassword", username = "Paz_bo", password = { env = "TEST_STORE_PASSWORD" } }
+rate_limit = { requests_per_second = 2.0, max_concurrency = 1 }
+expected_files = 3
+
+[[stores]]
+name = "shufersal"
+website = { type = "Shufersal" }
+enabled = false
+
+[[stores]]
+name = "king_store"
+website = { type = "SimpleJsonToGet", initial_url = "https://www.kingstore.co.il/Food_Law/MainIO_Hok.aspx", download_prefix = "https://www.kingstore.co.il/Food_Law/Download/" }
+"#,
+        )
+        .unwrap();
+        let stores = load_store_configs(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            stores.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(),
+            vec!["paz", "king_store"]
+        );
+        assert_eq!(stores[0].rate_limit.unwrap().max_concurrency, 1);
+        assert_eq!(stores[0].expected_files, Some(3));
+        assert_eq!(stores[1].expected_files, None);
+        assert_eq!(
+            stores[1].website.host().as_deref(),
+            Some("www.kingstore.co.il")
+        );
+        let Website::PublishedPriceWithPassword { username, password } = &stores[0].website else {
+            panic!("Unexpected website {:?}", stores[0].website);
+        };
+        assert_eq!(username, "Paz_bo");
+        assert!(password.resolve().is_err());
+        std::env::set_var("TEST_STORE_PASSWORD", "secret");
+        assert_eq!(password.resolve().unwrap(), "secret");
+    }
 }
@@ -1,16 +1,23 @@
 use crate::file_info::*;
+use crate::http_fixture;
+use crate::manifest::{Manifest, MANIFEST_PATH};
 use crate::parallel_download::{self, Download};
+use crate::published_prices::{PublishedPricesClient, Search};
+use crate::rate_limit;
+use crate::reqwest_utils::{send_with_retries, RetryPolicy};
+use crate::run_summary::{RunSummary, StoreSummary};
 use crate::store::*;
 use anyhow::anyhow;
 use anyhow::Result;
 use chrono::Datelike;
 use futures::StreamExt; // 0.3.5
 use reqwest::header;
-use reqwest::{Client, Response}; // 0.10.6
+use reqwest::{Client, RequestBuilder, Response}; // 0.10.6
 use scraper::{ElementRef, Html, Selector};
 use serde_json::Value;
 use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::sync::Semaphore;
 use tracing::{debug, error, info, instrument, Instrument, Span};
 
@@ -19,30 +26,15 @@ async fn get_text(url: &str) -> Result<String> {
         .timeout(std::time::Duration::from_secs(10))
         .build()
         .unwrap();
-
-    // number of retries
-    for _ in 1..10 {
-        if let Ok(resp) = client.get(url).send().await {
-            if let Ok(text) = resp.text().await {
-                return Ok(text);
-            };
-        };
-    }
-    Err(anyhow!("Could not download {url} after retries"))
+    send_text(url, || client.get(http_fixture::url(url))).await
 }
 
-fn extract_csrf(html: String) -> Result<String> {
-    let document = Html::parse_document(&html);
-    let selector = Selector::parse("meta[name=\"csrftoken\"]").unwrap();
-    let input = document.select(&selector).next().unwrap();
-    input
-        .value()
-        .attr("content")
-        .map(str::to_string)
-        .ok_or(anyhow!("Cannot extract csrf token"))
+// Sends the request with the shared retry policy, and reads the body of its response.
+async fn send_text(url: &str, request: impl Fn() -> RequestBuilder) -> Result<String> {
+    Ok(send_with_retries(&RetryPolicy::default(), url, request, Response::text, None).await?)
 }
 
-fn get_cookie_from_resp(resp: &Response) -> Result<String> {
+pub fn get_cookie_from_resp(resp: &Response) -> Result<String> {
     let cookie = resp
         .headers()
         .get(header::SET_COOKIE)
@@ -50,81 +42,37 @@ fn get_cookie_from_resp(resp: &Response) -> Result<String> {
     Ok(cookie.to_str()?.split(";").next().unwrap_or("").to_string())
 }
 
-fn get_headers() -> header::HeaderMap {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(
-        "Content-Type",
-        "application/x-www-form-urlencoded".parse().unwrap(),
-    );
-    return headers;
-}
-
 async fn get_downloads_publishedprice(
     store: &Store,
     username: &str,
     password: &str,
-    file_limit: Option<usize>,
+    selection: &FileSelection,
     download_semaphore: Arc<Semaphore>,
 ) -> Result<Vec<Download>> {
-    let client = Client::builder().cookie_store(true).build()?;
+    let client = PublishedPricesClient::for_user(username, password)?;
 
     let permit = download_semaphore.acquire_owned().await?;
-    // Main Page
-    let resp = client
-        .get("https://url.publishedprices.co.il/login")
-        .send()
-        .await?;
-
-    let csrftoken = extract_csrf(resp.text().await?)?;
-    let resp = client
-        .post("https://url.publishedprices.co.il/login/user")
-        .headers(get_headers())
-        .body(format!(
-            "r=&username={}&password={}&Submit=Sign+in&csrftoken={csrftoken}",
-            username, password
-        ))
-        .send()
-        .await?;
-    let cookie = get_cookie_from_resp(&resp)?;
-    let csrftoken = extract_csrf(resp.text().await?)?;
-
-    let mut headers = get_headers();
-    headers.insert(header::COOKIE, (&cookie).parse().unwrap());
-
-    let url = "https://url.publishedprices.co.il/file/json/dir";
-    let data = client.post(url).headers(headers.clone())
-    .body(format!("sEcho=1&iColumns=5&sColumns=%2C%2C%2C%2C&iDisplayStart=0&iDisplayLength=100000&mDataProp_0=fname&sSearch_0=&bRegex_0=false&bSearchable_0=true&bSortable_0=true&mDataProp_1=typeLabel&sSearch_1=&bRegex_1=false&bSearchable_1=true&bSortable_1=false&mDataProp_2=size&sSearch_2=&bRegex_2=false&bSearchable_2=true&bSortable_2=true&mDataProp_3=ftime&sSearch_3=&bRegex_3=false&bSearchable_3=true&bSortable_3=true&mDataProp_4=&sSearch_4=&bRegex_4=false&bSearchable_4=true&bSortable_4=false&sSearch=&bRegex=false&iSortingCols=0&cd=%2F&csrftoken={csrftoken}"))
-    .send().await?;
-    let text = data.text().await?;
-
+    let files = client.list_files(&Search::for_selection(selection)).await?;
+    let headers = client.headers().await?;
     drop(permit);
 
-    let json_root = serde_json::from_str::<Value>(&text)?;
-    let downloads: Vec<Download> = FileInfo::from_str_iter(
-        json_root["aaData"]
-            .as_array()
-            .ok_or(anyhow!("Empty json array"))?
-            .into_iter()
-            .map(|elem| elem["fname"].to_string().replace("\"", "")),
-        file_limit,
-    )
-    .map(|file_info| parallel_download::Download {
-        store: store.name.to_string(),
-        path: format!(
-            "https://url.publishedprices.co.il/file/d/{}",
-            file_info.filename
-        ),
-        headers: Some(headers.clone()),
-        dest: format!("data_raw/{}/{}", store.name, file_info.filename),
-    })
-    .collect();
+    // Files in folders keep their path as source.
+    let downloads: Vec<Download> = FileInfo::from_str_iter(files.into_iter(), selection)
+        .map(|file_info| parallel_download::Download {
+            store: store.name.to_string(),
+            path: PublishedPricesClient::download_url(&file_info.source),
+            headers: Some(headers.clone()),
+            dest: selection.dest(&store.name, &file_info),
+            session: Some(client.clone()),
+        })
+        .collect();
 
-    return Ok(downloads);
+    Ok(downloads)
 }
 
 async fn get_downloads_simple_json_to_get(
     store: &Store,
-    file_limit: Option<usize>,
+    selection: &FileSelection,
     initial_url: &str,
     download_prefix: &str,
 ) -> Result<Vec<Download>> {
@@ -146,13 +94,14 @@ async fn get_downloads_simple_json_to_get(
                     .expect("not a string")
                     .to_string()
             }),
-        file_limit,
+        selection,
     )
     .map(|fi| Download {
         store: store.name.to_string(),
-        dest: format!("data_raw/{}/{}", store.name, fi.filename),
+        dest: selection.dest(&store.name, &fi),
         path: format!("{}{}", download_prefix, fi.filename),
         headers: None,
+        session: None,
     })
     .collect();
     Ok(downloads)
@@ -160,7 +109,7 @@ async fn get_downloads_simple_json_to_get(
 
 async fn get_downloads_superpharm(
     store: &Store,
-    file_limit: Option<usize>,
+    selection: &FileSelection,
 ) -> Result<Vec<Download>> {
     // The flow is complex here.
     // First, we get the total number of pages.
@@ -188,43 +137,47 @@ async fn get_downloads_superpharm(
     let fetches = futures::stream::iter(1..(num_pages + 1))
         .map(|page| async move {
             let path = format!("http://prices.super-pharm.co.il/?page={page}");
-            match reqwest::get(&path).await {
-                Ok(resp) => {
-                    let cookie = get_cookie_from_resp(&resp).unwrap();
-                    match &resp.text().await {
-                        Ok(html) => {
-                            debug!("Success reading {path}");
-                            let selector = Selector::parse(".file_list tr").unwrap();
-                            Ok(Html::parse_document(&html)
-                                .select(&selector)
-                                .skip(1)
-                                .map(|elem| {
-                                    let mut iter = elem.children().into_iter();
-                                    let filename: String = ElementRef::wrap(iter.nth(1).unwrap())
-                                        .unwrap()
-                                        .text()
-                                        .collect();
-                                    let link: String = ElementRef::wrap(
-                                        ElementRef::wrap(iter.nth(3).unwrap())
-                                            .unwrap()
-                                            .children()
-                                            .nth(0)
-                                            .unwrap(),
-                                    )
-                                    .unwrap()
-                                    .value()
-                                    .attr("href")
-                                    .unwrap()
-                                    .to_string();
-                                    (filename, link, cookie.clone())
-                                })
-                                .collect::<Vec<(String, String, String)>>())
-                        }
-                        Err(_) => Err(format!("ERROR reading {}", path)),
-                    }
-                }
-                Err(_) => Err(format!("ERROR downloading {}", path)),
-            }
+            let client = Client::new();
+            // Each page comes with the cookie its links must be fetched with.
+            let (cookie, html) = send_with_retries(
+                &RetryPolicy::default(),
+                &path,
+                || client.get(http_fixture::url(&path)),
+                |resp| async move {
+                    let cookie = get_cookie_from_resp(&resp);
+                    resp.text().await.map(|html| (cookie, html))
+                },
+                None,
+            )
+            .await
+            .map_err(|e| format!("ERROR downloading {path}: {e}"))?;
+            let cookie = cookie.map_err(|e| format!("ERROR reading {path}: {e}"))?;
+            debug!("Success reading {path}");
+            let selector = Selector::parse(".file_list tr").unwrap();
+            Ok(Html::parse_document(&html)
+                .select(&selector)
+                .skip(1)
+                .map(|elem| {
+                    let mut iter = elem.children().into_iter();
+                    let filename: String = ElementRef::wrap(iter.nth(1).unwrap())
+                        .unwrap()
+                        .text()
+                        .collect();
+                    let link: String = ElementRef::wrap(
+                        ElementRef::wrap(iter.nth(3).unwrap())
+                            .unwrap()
+                            .children()
+                            .nth(0)
+                            .unwrap(),
+                    )
+                    .unwrap()
+                    .value()
+                    .attr("href")
+                    .unwrap()
+                    .to_string();
+                    (filename, link, cookie.clone())
+                })
+                .collect::<Vec<(String, String, String)>>())
         })
         .buffer_unordered(32)
         .collect::<Vec<Result<Vec<(String, String, String)>, String>>>();
@@ -249,55 +202,51 @@ async fn get_downloads_superpharm(
                     .with_source(&link)
                     .with_cookie(&cookie)
             })
-            .filter(|file_info| file_info.is_interesting())
+            .filter(|file_info| selection.is_interesting(file_info))
             .collect(),
-        file_limit,
+        selection,
     );
-    let downloads = futures::stream::iter(file_infos)
+    // Each link gives the URL of the file, which is downloaded with the same cookie.
+    let resolved = futures::stream::iter(file_infos)
         .map(|file_info| async move {
             let mut headers = header::HeaderMap::new();
-            headers.insert(header::COOKIE, (&file_info.cookie).parse().unwrap());
-            let resp = Client::new()
-                .get(format!(
-                    "http://prices.super-pharm.co.il{}",
-                    file_info.source
-                ))
-                .headers(headers)
-                .send()
-                .await
-                .unwrap();
-
-            let url = {
-                let text = resp.text().await.unwrap();
-                let json_root = serde_json::from_str::<Value>(&text).unwrap();
-                let href = json_root
-                    .as_object()
-                    .unwrap()
-                    .get("href")
-                    .unwrap()
-                    .as_str()
-                    .unwrap();
-                format!("http://prices.super-pharm.co.il{href}")
-            };
-            let mut headers = header::HeaderMap::new();
-            headers.insert(header::COOKIE, (&file_info.cookie).parse().unwrap());
-            Download {
+            headers.insert(header::COOKIE, file_info.cookie.parse()?);
+            let url = format!("http://prices.super-pharm.co.il{}", file_info.source);
+            let client = Client::new();
+            let text = send_text(&url, || {
+                client.get(http_fixture::url(&url)).headers(headers.clone())
+            })
+            .await?;
+            let href = serde_json::from_str::<Value>(&text)?
+                .get("href")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .ok_or(anyhow!("No href in the response of {url}"))?;
+            Ok(Download {
                 store: store.name.to_string(),
-                dest: format!("data_raw/{}/{}", store.name, file_info.filename),
+                dest: selection.dest(&store.name, &file_info),
                 headers: Some(headers),
-                path: url,
-            }
+                session: None,
+                path: format!("http://prices.super-pharm.co.il{href}"),
+            })
         })
         .buffer_unordered(32)
-        .collect::<Vec<Download>>()
+        .collect::<Vec<Result<Download>>>()
         .await;
 
+    let mut downloads = Vec::new();
+    for download in resolved {
+        match download {
+            Ok(download) => downloads.push(download),
+            Err(e) => error!("Error: {e}"),
+        }
+    }
     Ok(downloads)
 }
 
 async fn get_downloads_netiv_hahesed(
     store: &Store,
-    file_limit: Option<usize>,
+    selection: &FileSelection,
 ) -> Result<Vec<Download>> {
     fn get_links(document: &Html) -> Vec<String> {
         let selector = Selector::parse("#download_content a").unwrap();
@@ -343,9 +292,25 @@ async fn get_downloads_netiv_hahesed(
         let view_state_generator = get_value(&document, "#__VIEWSTATEGENERATOR")?;
         let event_validation = get_value(&document, "#__EVENTVALIDATION")?;
 
-        for i in 1..=7 {
-            let date = date.checked_sub_days(chrono::Days::new(i)).unwrap();
-
+        // When archiving, every day of the requested window is fetched instead.
+        let days = match selection.archive {
+            true => {
+                let since = selection
+                    .since
+                    .unwrap_or(date - chrono::Days::new(7))
+                    .min(date);
+                let until = selection.until.unwrap_or(date).min(date);
+                since
+                    .iter_days()
+                    .take_while(|day| *day <= until)
+                    .filter(|day| *day != date)
+                    .collect::<Vec<_>>()
+            }
+            false => (1..=7)
+                .map(|i| date.checked_sub_days(chrono::Days::new(i)).unwrap())
+                .collect(),
+        };
+        for date in days {
             let date = format!("{}/{}/{}", date.day(), date.month(), date.year());
             let client = Client::builder()
                 .redirect(reqwest::redirect::Policy::none())
@@ -357,24 +322,23 @@ async fn get_downloads_netiv_hahesed(
             params.insert("__EVENTVALIDATION", event_validation);
             params.insert("ctl00$MainContent$MainContent_txtDate", &date);
 
-            let html = client
-                .post("http://141.226.222.202/")
-                .form(&params)
-                .send()
-                .await?
-                .text()
-                .await?;
+            let url = "http://141.226.222.202/";
+            let html = send_text(url, || client.post(http_fixture::url(url)).form(&params)).await?;
             let document = Html::parse_document(&html);
             all_links.extend(get_links(&document));
         }
     }
 
-    let downloads: Vec<Download> = FileInfo::from_str_iter(all_links.into_iter(), file_limit)
+    // Days can list the same files.
+    all_links.sort();
+    all_links.dedup();
+    let downloads: Vec<Download> = FileInfo::from_str_iter(all_links.into_iter(), selection)
         .map(|fi| Download {
             store: store.name.to_string(),
-            dest: format!("data_raw/{}/{}", store.name, fi.filename),
+            dest: selection.dest(&store.name, &fi),
             path: format!("http://141.226.222.202/prices/{}", fi.filename),
             headers: None,
+            session: None,
         })
         .collect();
 
@@ -383,54 +347,71 @@ async fn get_downloads_netiv_hahesed(
 
 async fn get_downloads_publish_price(
     store: &Store,
-    file_limit: Option<usize>,
+    selection: &FileSelection,
     url: &str,
 ) -> Result<Vec<Download>> {
-    // e.g. http://publishprice.mega.co.il/20221031/
-    let data_url = {
+    // A folder per day, e.g. http://publishprice.mega.co.il/20221031/
+    let days = {
         debug!("Downloading {url} ...");
         let html = get_text(url).await?;
         debug!("Done downloading {url}.");
         let selector = Selector::parse("#files tr:nth-child(4) a").unwrap();
         let document = Html::parse_document(&html);
-        let date = document
+        document
             .select(&selector)
-            .last()
-            .unwrap()
-            .value()
-            .attr("href")
-            .unwrap();
-        format!("{url}{date}")
+            .filter_map(|a| a.value().attr("href"))
+            .map(str::to_string)
+            .collect::<Vec<_>>()
     };
+    // The latest day, or when archiving, every day of the requested window.
+    let days = match selection.archive {
+        true => days
+            .into_iter()
+            .filter(|day| {
+                chrono::NaiveDate::parse_from_str(day.trim_end_matches('/'), "%Y%m%d")
+                    .is_ok_and(|day| selection.includes_day(day))
+            })
+            .collect(),
+        false => days.into_iter().last().into_iter().collect::<Vec<_>>(),
+    };
+    if days.is_empty() {
+        return Err(anyhow!("No day folder found in {url}"));
+    }
 
-    debug!("Downloading {data_url}");
-    let html = reqwest::get(&data_url).await?.text().await?;
-    debug!("Done.");
-
-    let selector = Selector::parse("#files a").unwrap();
-    let document = Html::parse_document(&html);
+    let mut downloads = Vec::new();
+    for day in days {
+        let data_url = format!("{url}{day}");
+        debug!("Downloading {data_url}");
+        let html = get_text(&data_url).await?;
+        debug!("Done.");
 
-    let downloads: Vec<Download> = FileInfo::from_str_iter(
-        document
-            .select(&selector)
-            .skip(3) // header
-            .map(|a| a.value().attr("href").unwrap().to_string()),
-        file_limit,
-    )
-    .map(|fi| Download {
-        store: store.name.to_string(),
-        dest: format!("data_raw/{}/{}", store.name, fi.filename),
-        path: format!("{data_url}{}", fi.filename),
-        headers: None,
-    })
-    .collect();
+        let selector = Selector::parse("#files a").unwrap();
+        let document = Html::parse_document(&html);
+        downloads.extend(
+            FileInfo::from_str_iter(
+                document
+                    .select(&selector)
+                    .skip(3) // header
+                    .filter_map(|a| a.value().attr("href"))
+                    .map(str::to_string),
+                selection,
+            )
+            .map(|fi| Download {
+                store: store.name.to_string(),
+                dest: selection.dest(&store.name, &fi),
+                path: format!("{data_url}{}", fi.filename),
+                headers: None,
+                session: None,
+            }),
+        );
+    }
 
     Ok(downloads)
 }
 
 async fn get_downloads_matrix_catalog(
     store: &Store,
-    file_limit: Option<usize>,
+    selection: &FileSelection,
     chain: &str,
 ) -> Result<Vec<Download>> {
     let html = get_text("http://matrixcatalog.co.il/NBCompetitionRegulations.aspx").await?;
@@ -471,13 +452,14 @@ async fn get_downloads_matrix_catalog(
                 .unwrap()
                 .to_string()
             }),
-        file_limit,
+        selection,
     )
     .map(|fi| parallel_download::Download {
         store: store.name.to_string(),
-        dest: format!("data_raw/{}/{}", store.name, fi.filename),
+        dest: selection.dest(&store.name, &fi),
         path: format!("{}{}", "http://matrixcatalog.co.il/", fi.source),
         headers: None,
+        session: None,
     })
     .collect();
 
@@ -486,7 +468,7 @@ async fn get_downloads_matrix_catalog(
 
 async fn get_downloads_shufersal(
     store: &Store,
-    file_limit: Option<usize>,
+    selection: &FileSelection,
 ) -> Result<Vec<Download>> {
     let html = get_text("http://prices.shufersal.co.il/FileObject/UpdateCategory?page=1").await?;
     let selector = Selector::parse("tfoot a").unwrap();
@@ -506,20 +488,16 @@ async fn get_downloads_shufersal(
         .map(|page| async move {
             let path =
                 format!("http://prices.shufersal.co.il/FileObject/UpdateCategory?page={page}");
-            match reqwest::get(&path).await {
-                Ok(resp) => match resp.text().await {
-                    Ok(html) => {
-                        debug!("Success reading {path}");
-                        let selector = Selector::parse("tbody a").unwrap();
-                        Ok(Html::parse_document(&html)
-                            .select(&selector)
-                            .map(|elem| elem.value().attr("href").ok_or("err").unwrap().to_string())
-                            .collect::<Vec<String>>())
-                    }
-                    Err(_) => Err(format!("ERROR reading {}", path)),
-                },
-                Err(_) => Err(format!("ERROR downloading {}", path)),
-            }
+            let html = get_text(&path)
+                .await
+                .map_err(|e| format!("ERROR downloading {path}: {e}"))?;
+            debug!("Success reading {path}");
+            let selector = Selector::parse("tbody a").unwrap();
+            Ok(Html::parse_document(&html)
+                .select(&selector)
+                .filter_map(|elem| elem.value().attr("href"))
+                .map(str::to_string)
+                .collect::<Vec<String>>())
         })
         .buffer_unordered(32)
         .collect::<Vec<Result<Vec<String>, String>>>();
@@ -535,74 +513,112 @@ async fn get_downloads_shufersal(
         }
     }
 
-    let downloads: Vec<Download> = FileInfo::from_str_iter(all_links.into_iter(), file_limit)
+    let downloads: Vec<Download> = FileInfo::from_str_iter(all_links.into_iter(), selection)
         .map(|fi| parallel_download::Download {
             store: store.name.to_string(),
-            dest: format!("data_raw/{}/{}", store.name, fi.filename),
+            dest: selection.dest(&store.name, &fi),
             path: fi.source,
             headers: None,
+            session: None,
         })
         .collect();
     Ok(downloads)
 }
 
-#[instrument(fields(store_name=store.name), skip_all)]
-async fn download_store_data(
-    store: Store,
-    _dir: String,
-    quick: bool,
-    file_limit: Option<usize>,
+async fn get_downloads(
+    store: &Store,
+    selection: &FileSelection,
     download_semaphore: Arc<Semaphore>,
-) -> Result<()> {
-    info!("Start handling store");
-    let downloads = match store.website {
-        Website::PublishedPrice(username) => {
-            get_downloads_publishedprice(
-                &store,
-                username,
-                "",
-                file_limit,
-                download_semaphore.clone(),
-            )
-            .await?
+) -> Result<Vec<Download>> {
+    match &store.website {
+        Website::PublishedPrice { username } => {
+            get_downloads_publishedprice(store, username, "", selection, download_semaphore).await
         }
-        Website::PublishedPriceWithPassword(username, password) => {
+        Website::PublishedPriceWithPassword { username, password } => {
             get_downloads_publishedprice(
-                &store,
+                store,
                 username,
-                password,
-                file_limit,
-                download_semaphore.clone(),
+                &password.resolve()?,
+                selection,
+                download_semaphore,
             )
-            .await?
+            .await
         }
-        Website::Shufersal => get_downloads_shufersal(&store, file_limit).await?,
-        Website::SimpleJsonToGet(initial_url, download_prefix) => {
-            get_downloads_simple_json_to_get(&store, file_limit, initial_url, download_prefix)
-                .await?
+        Website::Shufersal => get_downloads_shufersal(store, selection).await,
+        Website::SimpleJsonToGet {
+            initial_url,
+            download_prefix,
+        } => get_downloads_simple_json_to_get(store, selection, initial_url, download_prefix).await,
+        Website::MatrixCatalog { chain } => {
+            get_downloads_matrix_catalog(store, selection, chain).await
         }
-        Website::MatrixCatalog(chain) => {
-            get_downloads_matrix_catalog(&store, file_limit, chain).await?
+        Website::PublishPrice { main_page } => {
+            get_downloads_publish_price(store, selection, main_page).await
         }
-        Website::PublishPrice(url) => get_downloads_publish_price(&store, file_limit, url).await?,
-        Website::NetivHahesed => get_downloads_netiv_hahesed(&store, file_limit).await?,
-        Website::SuperPharm => get_downloads_superpharm(&store, file_limit).await?,
+        Website::NetivHahesed => get_downloads_netiv_hahesed(store, selection).await,
+        Website::SuperPharm => get_downloads_superpharm(store, selection).await,
+    }
+}
+
+#[instrument(fields(store_name=store.name.as_str()), skip_all)]
+async fn download_store_data(
+    store: Store,
+    _dir: String,
+    quick: bool,
+    selection: FileSelection,
+    download_semaphore: Arc<Semaphore>,
+    manifest: Arc<Mutex<Manifest>>,
+) -> StoreSummary {
+    info!("Start handling store");
+    let start = Instant::now();
+    let mut summary = StoreSummary {
+        store: store.name.clone(),
+        ..Default::default()
     };
-    info!("Found a total of {} elements", downloads.len());
-    if quick {
-        return Ok(());
+    match get_downloads(&store, &selection, download_semaphore.clone()).await {
+        Ok(downloads) => {
+            info!("Found a total of {} elements", downloads.len());
+            summary.listed = downloads.len();
+            if !quick {
+                let stats =
+                    parallel_download::parallel_download(downloads, download_semaphore, manifest)
+                        .await;
+                summary.downloaded = stats.downloaded;
+                summary.skipped = stats.skipped;
+                summary.bytes = stats.bytes;
+                summary.failed = stats.failures.len();
+                summary.failures = stats.failures;
+            }
+        }
+        Err(e) => {
+            error!("Cannot list the files: {e:#}");
+            summary.error = Some(format!("{e:#}"));
+        }
     }
-    parallel_download::parallel_download(downloads, download_semaphore).await;
-    Ok(())
+    summary.duration_secs = start.elapsed().as_secs_f64();
+    summary
 }
 
 pub async fn download_all_stores_data(
     stores: &Vec<Store>,
     quick: bool,
-    file_limit: Option<usize>,
+    selection: &FileSelection,
     dir: String,
-) {
+) -> RunSummary {
     let download_semaphore = Arc::new(Semaphore::new(30));
+    for store in stores {
+        if let (Some(host), Some(limit)) = (store.website.host(), store.rate_limit) {
+            rate_limit::configure(&host, limit);
+        }
+    }
+    let manifest = match Manifest::load(MANIFEST_PATH) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            error!("Couldn't read {MANIFEST_PATH}, all files will be downloaded: {e}");
+            Manifest::default()
+        }
+    };
+    let manifest = Arc::new(Mutex::new(manifest));
     let tasks: Vec<_> = stores
         .iter()
         .map(|store| {
@@ -612,8 +628,9 @@ pub async fn download_all_stores_data(
                     store.clone(),
                     dir.clone(),
                     quick,
-                    file_limit,
+                    selection.clone(),
                     download_semaphore.clone(),
+                    manifest.clone(),
                 )
                 .instrument(span),
             )
@@ -623,13 +640,256 @@ pub async fn download_all_stores_data(
         "All tasks are spawned. Total tasks spawned: {}.",
         tasks.len()
     );
-    for task in tasks {
-        // let x = task.await;
+    let mut summary = RunSummary::default();
+    for (store, task) in stores.iter().zip(tasks) {
         match task.await {
-            Ok(Ok(())) => (),
-            Ok(Err(err)) => error!("Error: {err}"),
-            Err(err) => error!("Error: {err}"),
+            Ok(store_summary) => summary.stores.push(store_summary),
+            Err(err) => {
+                error!("Error: {err}");
+                summary.stores.push(StoreSummary {
+                    store: store.name.clone(),
+                    error: Some(err.to_string()),
+                    ..Default::default()
+                });
+            }
         };
     }
+    if let Err(e) = manifest.lock().unwrap().save(MANIFEST_PATH) {
+        error!("Couldn't write {MANIFEST_PATH}: {e}");
+    }
     info!("Processing complete.");
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn store(name: &str, website: Website) -> Store {
+        Store {
+            name: name.to_string(),
+            website,
+            enabled: true,
+            rate_limit: None,
+            expected_files: None,
+        }
+    }
+
+    fn paths(downloads: &[Download]) -> Vec<&str> {
+        let mut paths: Vec<&str> = downloads.iter().map(|d| d.path.as_str()).collect();
+        paths.sort();
+        paths
+    }
+
+    #[tokio::test]
+    async fn test_publishedprice() {
+        http_fixture::replay_for_tests();
+        let store = store(
+            "tiv_taam",
+            Website::PublishedPrice {
+                username: "TivTaam".into(),
+            },
+        );
+        let downloads = get_downloads_publishedprice(
+            &store,
+            "TivTaam",
+            "",
+            &FileSelection::default(),
+            Arc::new(Semaphore::new(1)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            paths(&downloads),
+            vec![
+                "https://url.publishedprices.co.il/file/d/PriceFull7290873255550-001-202306110500.gz",
+                "https://url.publishedprices.co.il/file/d/PromoFull7290873255550-001-202306110500.gz",
+                "https://url.publishedprices.co.il/file/d/Stores7290873255550-000-202306110500.xml",
+            ]
+        );
+        for download in &downloads {
+            let headers = download.headers.as_ref().unwrap();
+            assert_eq!(headers[header::COOKIE], "cftpSID=session-id");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shufersal() {
+        http_fixture::replay_for_tests();
+        let store = store("shufersal", Website::Shufersal);
+        let downloads = get_downloads_shufersal(&store, &FileSelection::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            paths(&downloads),
+            vec![
+                "https://pricesprodpublic.blob.core.windows.net/pricefull/PriceFull7290027600007-001-202306110300.gz?sv=2014-02-14&sr=b",
+                "https://pricesprodpublic.blob.core.windows.net/pricefull/PriceFull7290027600007-002-202306110300.gz?sv=2014-02-14&sr=b",
+                "https://pricesprodpublic.blob.core.windows.net/promofull/PromoFull7290027600007-002-202306110300.gz?sv=2014-02-14&sr=b",
+                "https://pricesprodpublic.blob.core.windows.net/stores/Stores7290027600007-000-202306110201.xml.gz?sv=2014-02-14&sr=b",
+            ]
+        );
+        assert!(downloads
+            .iter()
+            .any(|d| d.dest == "data_raw/shufersal/Stores7290027600007-000-202306110201.xml.gz"));
+    }
+
+    #[tokio::test]
+    async fn test_simple_json_to_get() {
+        http_fixture::replay_for_tests();
+        let store = store(
+            "shuk_hayir",
+            Website::SimpleJsonToGet {
+                initial_url: "http://shuk-hayir.binaprojects.com/MainIO_Hok.aspx".into(),
+                download_prefix: "http://shuk-hayir.binaprojects.com/Download/".into(),
+            },
+        );
+        let downloads = get_downloads_simple_json_to_get(
+            &store,
+            &FileSelection::default(),
+            "http://shuk-hayir.binaprojects.com/MainIO_Hok.aspx",
+            "http://shuk-hayir.binaprojects.com/Download/",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            paths(&downloads),
+            vec![
+                "http://shuk-hayir.binaprojects.com/Download/PriceFull7290876100000-001-202306110000.zip",
+                "http://shuk-hayir.binaprojects.com/Download/PromoFull7290876100000-001-202306110000.zip",
+                "http://shuk-hayir.binaprojects.com/Download/Stores7290876100000-000-202306110000.zip",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_matrix_catalog() {
+        http_fixture::replay_for_tests();
+        let store = store(
+            "victory",
+            Website::MatrixCatalog {
+                chain: "ויקטורי".into(),
+            },
+        );
+        let downloads = get_downloads_matrix_catalog(&store, &FileSelection::default(), "ויקטורי")
+            .await
+            .unwrap();
+        assert_eq!(
+            paths(&downloads),
+            vec![
+                "http://matrixcatalog.co.il/CompetitionRegulationsFiles/latest/7290696200003/PriceFull7290696200003-001-202306110500.xml.gz",
+                "http://matrixcatalog.co.il/CompetitionRegulationsFiles/latest/7290696200003/PromoFull7290696200003-001-202306110500.xml.gz",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_price() {
+        http_fixture::replay_for_tests();
+        let store = store(
+            "mega",
+            Website::PublishPrice {
+                main_page: "http://publishprice.mega.co.il/".into(),
+            },
+        );
+        // Only the latest day.
+        let downloads = get_downloads_publish_price(
+            &store,
+            &FileSelection::default(),
+            "http://publishprice.mega.co.il/",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            paths(&downloads),
+            vec![
+                "http://publishprice.mega.co.il/20230611/PriceFull7290055700007-0001-202306110200.gz",
+                "http://publishprice.mega.co.il/20230611/PromoFull7290055700007-0001-202306110200.gz",
+                "http://publishprice.mega.co.il/20230611/Stores7290055700007-000-202306110200.xml",
+            ]
+        );
+
+        // Every day of the window.
+        let selection = FileSelection {
+            archive: true,
+            since: NaiveDate::from_ymd_opt(2023, 6, 10),
+            until: NaiveDate::from_ymd_opt(2023, 6, 11),
+            ..Default::default()
+        };
+        let downloads =
+            get_downloads_publish_price(&store, &selection, "http://publishprice.mega.co.il/")
+                .await
+                .unwrap();
+        assert_eq!(
+            paths(&downloads),
+            vec![
+                "http://publishprice.mega.co.il/20230610/PriceFull7290055700007-0001-202306100200.gz",
+                "http://publishprice.mega.co.il/20230610/PromoFull7290055700007-0001-202306100200.gz",
+                "http://publishprice.mega.co.il/20230611/PriceFull7290055700007-0001-202306110200.gz",
+                "http://publishprice.mega.co.il/20230611/PromoFull7290055700007-0001-202306110200.gz",
+                "http://publishprice.mega.co.il/20230611/Stores7290055700007-000-202306110200.xml",
+            ]
+        );
+        assert!(downloads
+            .iter()
+            .filter(|d| d.path.contains("/20230610/"))
+            .all(|d| d.dest.starts_with("archive/mega/2023-06-10/")));
+    }
+
+    #[tokio::test]
+    async fn test_netiv_hahesed() {
+        http_fixture::replay_for_tests();
+        let store = store("netiv_hahesed", Website::NetivHahesed);
+        let downloads = get_downloads_netiv_hahesed(&store, &FileSelection::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            paths(&downloads),
+            vec![
+                "http://141.226.222.202/prices/PriceFull7290058179875-001-202306100400.xml",
+                "http://141.226.222.202/prices/PromoFull7290058179875-001-202306100400.xml",
+                "http://141.226.222.202/prices/Stores7290058179875-000-202306110400.xml",
+            ]
+        );
+
+        let selection = FileSelection {
+            archive: true,
+            since: NaiveDate::from_ymd_opt(2023, 6, 9),
+            until: NaiveDate::from_ymd_opt(2023, 6, 10),
+            ..Default::default()
+        };
+        let downloads = get_downloads_netiv_hahesed(&store, &selection)
+            .await
+            .unwrap();
+        assert_eq!(
+            paths(&downloads),
+            vec![
+                "http://141.226.222.202/prices/PriceFull7290058179875-001-202306090400.xml",
+                "http://141.226.222.202/prices/PriceFull7290058179875-001-202306100400.xml",
+                "http://141.226.222.202/prices/PromoFull7290058179875-001-202306090400.xml",
+                "http://141.226.222.202/prices/PromoFull7290058179875-001-202306100400.xml",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_superpharm() {
+        http_fixture::replay_for_tests();
+        let store = store("superpharm", Website::SuperPharm);
+        let downloads = get_downloads_superpharm(&store, &FileSelection::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            paths(&downloads),
+            vec![
+                "http://prices.super-pharm.co.il/Download/final/PriceFull7290172900007-001-202306110500.gz?token=abc",
+                "http://prices.super-pharm.co.il/Download/final/StoresFull7290172900007-000-202306110500.gz?token=abc",
+            ]
+        );
+        for download in &downloads {
+            let headers = download.headers.as_ref().unwrap();
+            assert_eq!(headers[header::COOKIE], "SuperPharmPrices=page-session");
+        }
+    }
 }
@@ -0,0 +1,204 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::price::Price;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Unit {
+    Gram,
+    Milliliter,
+    Unit,
+}
+
+impl Unit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Unit::Gram => "g",
+            Unit::Milliliter => "ml",
+            Unit::Unit => "unit",
+        }
+    }
+}
+
+// A quantity in grams, milliliters or units.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Quantity {
+    pub amount: f64,
+    pub unit: Unit,
+}
+
+lazy_static! {
+    // Spellings of units found in the chains files, after removing quotes and spaces.
+    static ref UNITS: HashMap<&'static str, (f64, Unit)> = {
+        let mut map = HashMap::new();
+
+        for gram in ["גרם", "גרמים", "גר", "ג", "gr", "g", "gram"] {
+            map.insert(gram, (1.0, Unit::Gram));
+        }
+        for kilogram in ["קג", "קילוגרם", "קילוגרמים", "קילו", "kg"] {
+            map.insert(kilogram, (1000.0, Unit::Gram));
+        }
+        for milliliter in ["מל", "מיליליטר", "מיליליטרים", "מלל", "סמק", "ml"] {
+            map.insert(milliliter, (1.0, Unit::Milliliter));
+        }
+        for liter in ["ליטר", "ליטרים", "ל", "lt", "l"] {
+            map.insert(liter, (1000.0, Unit::Milliliter));
+        }
+        for unit in ["יחידה", "יחידות", "יח", "unit", "units"] {
+            map.insert(unit, (1.0, Unit::Unit));
+        }
+        map
+    };
+}
+
+// Parses a unit, optionally prefixed with an amount: "גרם", "ק\"ג", "100 גרם", "יח'".
+pub fn parse_unit(s: &str) -> Option<Quantity> {
+    let s: String = s
+        .chars()
+        .filter(|c| !matches!(c, '"' | '\'' | '״' | '׳' | '`' | ' '))
+        .collect::<String>()
+        .to_lowercase();
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (amount, unit) = s.split_at(unit_start);
+    let unit = unit.trim_end_matches('.');
+    let amount = match amount {
+        "" => 1.0,
+        amount => amount.parse::<f64>().ok()?,
+    };
+    let (factor, unit) = UNITS.get(unit)?;
+    Some(Quantity {
+        amount: amount * factor,
+        unit: *unit,
+    })
+}
+
+// Chains put the unit either in UnitQty or in UnitOfMeasure, and the amount in Quantity.
+// For items sold by unit, QtyInPackage is the number of units in a pack.
+pub fn parse_quantity(
+    quantity: &str,
+    unit_qty: &str,
+    unit_of_measure: &str,
+    qty_in_package: &str,
+) -> Option<Quantity> {
+    // UnitOfMeasure is the unit the price per measure is given for, e.g. "100 גרם": only its
+    // unit is used, not its amount.
+    let unit_of_measure =
+        unit_of_measure.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.' || c == ' ');
+    let unit = parse_unit(unit_qty).or_else(|| parse_unit(unit_of_measure))?;
+    let quantity = quantity
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|q| *q > 0.0)
+        .unwrap_or(1.0);
+    let qty_in_package = qty_in_package
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|q| *q > 1.0);
+    let amount = match (unit.unit, qty_in_package) {
+        (Unit::Unit, Some(qty_in_package)) if quantity == 1.0 => qty_in_package,
+        _ => quantity * unit.amount,
+    };
+    Some(Quantity {
+        amount,
+        unit: unit.unit,
+    })
+}
+
+// The price per 100g, per 100ml or per unit.
+pub fn normalized_price(price: Price, quantity: &Quantity) -> Option<Price> {
+    if quantity.amount <= 0.0 {
+        return None;
+    }
+    let per = match quantity.unit {
+        Unit::Gram | Unit::Milliliter => 100.0,
+        Unit::Unit => 1.0,
+    };
+    Some(Price(
+        (price.0 as f64 * per / quantity.amount).round() as i64
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantity(amount: f64, unit: Unit) -> Option<Quantity> {
+        Some(Quantity { amount, unit })
+    }
+
+    #[test]
+    fn test_parse_unit() {
+        assert_eq!(parse_unit("גרם"), quantity(1.0, Unit::Gram));
+        assert_eq!(parse_unit("100 גרם"), quantity(100.0, Unit::Gram));
+        assert_eq!(parse_unit("ק\"ג"), quantity(1000.0, Unit::Gram));
+        assert_eq!(parse_unit("ק״ג"), quantity(1000.0, Unit::Gram));
+        assert_eq!(parse_unit("קילוגרמים"), quantity(1000.0, Unit::Gram));
+        assert_eq!(parse_unit("ג'"), quantity(1.0, Unit::Gram));
+        assert_eq!(parse_unit("100 מ\"ל"), quantity(100.0, Unit::Milliliter));
+        assert_eq!(parse_unit("מיליליטר"), quantity(1.0, Unit::Milliliter));
+        assert_eq!(parse_unit("ליטר"), quantity(1000.0, Unit::Milliliter));
+        assert_eq!(parse_unit("1.5 ליטר"), quantity(1500.0, Unit::Milliliter));
+        assert_eq!(parse_unit("יח'"), quantity(1.0, Unit::Unit));
+        assert_eq!(parse_unit("יחידה"), quantity(1.0, Unit::Unit));
+        assert_eq!(parse_unit("Unknown"), None);
+        assert_eq!(parse_unit("לא ידוע"), None);
+        assert_eq!(parse_unit(""), None);
+    }
+
+    #[test]
+    fn test_parse_quantity_and_normalized_price() {
+        let yogurt = parse_quantity("200.00", "גרמים", "100 גרם", "1").unwrap();
+        assert_eq!(
+            yogurt,
+            Quantity {
+                amount: 200.0,
+                unit: Unit::Gram
+            }
+        );
+        assert_eq!(normalized_price(Price(590), &yogurt), Some(Price(295)));
+
+        let milk = parse_quantity("1.000", "ליטר", "100 מ\"ל", "").unwrap();
+        assert_eq!(
+            milk,
+            Quantity {
+                amount: 1000.0,
+                unit: Unit::Milliliter
+            }
+        );
+        assert_eq!(normalized_price(Price(690), &milk), Some(Price(69)));
+
+        let cheese = parse_quantity("200", "", "100 גרם", "").unwrap();
+        assert_eq!(
+            cheese,
+            Quantity {
+                amount: 200.0,
+                unit: Unit::Gram
+            }
+        );
+        assert_eq!(normalized_price(Price(1490), &cheese), Some(Price(745)));
+
+        let tomatoes = parse_quantity("1", "ק\"ג", "ק\"ג", "").unwrap();
+        assert_eq!(normalized_price(Price(990), &tomatoes), Some(Price(99)));
+
+        let baking_trays = parse_quantity("1", "יח'", "יחידה", "3").unwrap();
+        assert_eq!(
+            baking_trays,
+            Quantity {
+                amount: 3.0,
+                unit: Unit::Unit
+            }
+        );
+        assert_eq!(
+            normalized_price(Price(1200), &baking_trays),
+            Some(Price(400))
+        );
+
+        assert_eq!(parse_quantity("0", "לא ידוע", "", ""), None);
+    }
+}
@@ -1,5 +1,68 @@
-use anyhow::anyhow;
-use anyhow::Result;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use std::str::FromStr;
+
+use crate::price::Price;
+
+// The ways a file published by a chain can fail to be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    UnknownTag { tag: String, parent: String },
+    BadNumber { tag: String, value: String },
+    MissingTag(String),
+    BadEncoding(String),
+    InvalidXml(String),
+    UnknownRoot(String),
+    InvalidChain(String),
+}
+
+impl ParseError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ParseError::UnknownTag { .. } => "unknown_tag",
+            ParseError::BadNumber { .. } => "bad_number",
+            ParseError::MissingTag(_) => "missing_tag",
+            ParseError::BadEncoding(_) => "bad_encoding",
+            ParseError::InvalidXml(_) => "invalid_xml",
+            ParseError::UnknownRoot(_) => "unknown_root",
+            ParseError::InvalidChain(_) => "invalid_chain",
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::UnknownTag { tag, parent } => write!(f, "Unknown tag {tag} in {parent}"),
+            ParseError::BadNumber { tag, value } => write!(f, "Bad number {value:?} in {tag}"),
+            ParseError::MissingTag(tag) => write!(f, "Couldn't find tag {tag}"),
+            ParseError::BadEncoding(error) => write!(f, "Bad encoding: {error}"),
+            ParseError::InvalidXml(error) => write!(f, "Invalid xml: {error}"),
+            ParseError::UnknownRoot(tags) => write!(f, "Unknown root, first tags: {tags}"),
+            ParseError::InvalidChain(error) => write!(f, "Invalid chain: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn unknown_tag(n: &roxmltree::Node) -> ParseError {
+    ParseError::UnknownTag {
+        tag: n.tag_name().name().to_string(),
+        parent: n
+            .parent_element()
+            .map_or(String::new(), |p| p.tag_name().name().to_string()),
+    }
+}
+
+pub fn parse_number<T: FromStr>(tag: &str, value: &str) -> Result<T, ParseError> {
+    value
+        .trim()
+        .parse::<T>()
+        .map_err(|_| ParseError::BadNumber {
+            tag: tag.to_string(),
+            value: value.to_string(),
+        })
+}
 
 pub fn trim_whitespace(s: &str) -> String {
     let mut new_str = s.trim().to_owned();
@@ -13,35 +76,91 @@ pub fn trim_whitespace(s: &str) -> String {
 }
 
 pub fn to_string(n: &roxmltree::Node) -> String {
-    let mut s = match n.text().unwrap_or("") {
+    text_to_string(n.text().unwrap_or(""))
+}
+pub fn text_to_string(text: &str) -> String {
+    let mut s = match text {
         "לא ידוע" | "כללי" | "unknown" | "---" | "," => "".to_string(),
         s => trim_whitespace(s),
     };
     s = s.replace('\u{00A0}', " "); // remove non-breaking spaces
     s
 }
-pub fn to_country_code(n: &roxmltree::Node) -> String {
-    let mut s = to_string(n);
+pub fn text_to_country_code(text: &str) -> String {
+    let mut s = text_to_string(text);
     if let Some(country_code) = crate::country_code::to_country_code(&s) {
         s = country_code.to_string();
     }
     s
 }
-pub fn to_i32(n: &roxmltree::Node) -> Result<i32> {
-    Ok(n.text().unwrap_or("0").parse::<i32>()?)
+pub fn to_i32(n: &roxmltree::Node) -> Result<i32, ParseError> {
+    text_to_i32(n.tag_name().name(), n.text().unwrap_or(""))
+}
+pub fn text_to_i32(tag: &str, text: &str) -> Result<i32, ParseError> {
+    match text {
+        "" => Ok(0),
+        text => parse_number(tag, text),
+    }
+}
+
+pub fn to_price(n: &roxmltree::Node) -> Option<Price> {
+    Price::parse_optional(&text_to_string(n.text().unwrap_or("")))
+}
+// Empty and zero prices are unknown, but text that isn't a price is an error.
+pub fn text_to_price(tag: &str, text: &str) -> Result<Option<Price>, ParseError> {
+    let text = text_to_string(text);
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+    let price = text.parse::<Price>().map_err(|_| ParseError::BadNumber {
+        tag: tag.to_string(),
+        value: text.clone(),
+    })?;
+    Ok(Some(price).filter(|price| price.0 > 0))
+}
+
+pub fn to_f64(n: &roxmltree::Node) -> Option<f64> {
+    to_string(n).parse::<f64>().ok()
+}
+
+// Dates and hours are sometimes in two different tags, and sometimes in a single one.
+// The date separator also depends on the chain.
+pub fn to_datetime(date: &str, hour: &str) -> Option<NaiveDateTime> {
+    let date = date.trim().replace('T', " ");
+    let (date, inline_hour) = date.split_once(' ').unwrap_or((date.as_str(), ""));
+    let hour = match hour.trim() {
+        "" => inline_hour.trim(),
+        hour => hour,
+    };
+    let date = ["%Y-%m-%d", "%Y/%m/%d", "%d/%m/%Y"]
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(date, format).ok())?;
+    let time = match hour {
+        "" => NaiveTime::from_hms_opt(0, 0, 0)?,
+        hour => ["%H:%M:%S", "%H:%M:%S%.f", "%H:%M"]
+            .iter()
+            .find_map(|format| NaiveTime::parse_from_str(hour, format).ok())?,
+    };
+    Some(date.and_time(time))
 }
 
-pub fn to_child_content(node: &roxmltree::Node, tag: &str) -> Result<String> {
+pub fn to_child_content(node: &roxmltree::Node, tag: &str) -> Result<String, ParseError> {
     Ok(to_string(
         &node
             .children()
             .find(|elem| elem.tag_name().name() == tag)
-            .ok_or(anyhow!("Couldn't find tag {tag}"))?,
+            .ok_or(ParseError::MissingTag(tag.to_string()))?,
     ))
 }
 
-pub fn to_chain_id(node: &roxmltree::Node) -> Result<i64> {
-    let chain_id = node.text().unwrap_or("0").parse::<i64>()?;
+pub fn to_chain_id(node: &roxmltree::Node) -> Result<i64, ParseError> {
+    text_to_chain_id(node.tag_name().name(), node.text().unwrap_or(""))
+}
+pub fn text_to_chain_id(tag: &str, text: &str) -> Result<i64, ParseError> {
+    let chain_id: i64 = match text {
+        "" => 0,
+        text => parse_number(tag, text)?,
+    };
 
     Ok({
         if chain_id == 7290058103393 {
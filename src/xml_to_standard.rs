@@ -1,9 +1,9 @@
-use anyhow::anyhow;
-use anyhow::bail;
 use anyhow::Result;
 
 use encoding_rs::UTF_16LE;
+use itertools::Itertools;
 use models::*;
+use quick_xml::events::Event;
 use roxmltree::{Document, Node};
 use std::collections::HashMap;
 use std::io::prelude::*;
@@ -15,17 +15,35 @@ use tracing::Level;
 
 use crate::models;
 use crate::xml;
-fn validate_chain(chain: &Chain) {
-    assert!(chain.chain_id > 0);
-    assert!(!chain.subchains.is_empty());
+use crate::xml::ParseError;
+use israel_prices::cities;
+
+fn validate_chain(chain: &Chain) -> Result<(), ParseError> {
+    if chain.chain_id <= 0 {
+        return Err(ParseError::InvalidChain("missing chain id".to_string()));
+    }
+    if chain.subchains.is_empty() {
+        return Err(ParseError::InvalidChain(format!(
+            "no subchains in chain {}",
+            chain.chain_id
+        )));
+    }
     for subchain in &chain.subchains {
-        for store in &subchain.stores {
-            assert_ne!(store.store_id, 0)
+        if subchain.stores.iter().any(|store| store.store_id == 0) {
+            return Err(ParseError::InvalidChain(format!(
+                "missing store id in subchain {}",
+                subchain.subchain_id
+            )));
         }
     }
+    Ok(())
+}
+
+fn parse_document(contents: &str) -> Result<Document<'_>, ParseError> {
+    Document::parse(contents).map_err(|e| ParseError::InvalidXml(e.to_string()))
 }
 
-fn to_full_store(node: &roxmltree::Node, path: &str) -> Result<FullStore> {
+fn to_full_store(node: &roxmltree::Node) -> Result<FullStore> {
     let mut full_store = FullStore::default();
 
     for elem in node.children().filter(Node::is_element) {
@@ -45,95 +63,157 @@ fn to_full_store(node: &roxmltree::Node, path: &str) -> Result<FullStore> {
             "City" | "CITY" => full_store.store.city = xml::to_string(&elem),
             "ZIPCode" | "ZIPCODE" | "ZipCode" => full_store.store.zip_code = xml::to_string(&elem),
             "LastUpdateDate" | "LastUpdateTime" => (),
-            "Latitude" | "Longitude" => (), // These would be interesting, but are never set.
-            unknown => panic!("Unknown field: {unknown} in file {path}"), // TODO: do not panic in prod
+            // These are rarely set, and sometimes set to 0.
+            "Latitude" => full_store.store.latitude = xml::to_f64(&elem).filter(|v| *v != 0.0),
+            "Longitude" => full_store.store.longitude = xml::to_f64(&elem).filter(|v| *v != 0.0),
+            _ => Err(xml::unknown_tag(&elem))?,
         }
     }
+    if let Some(city) = cities::find(&full_store.store.city) {
+        full_store.store.city_id = Some(city.id);
+        full_store.store.city = city.hebrew.to_string();
+    }
     Ok(full_store)
 }
 
-#[instrument]
-pub fn hande_price_file(path: &str) -> Result<Prices> {
-    let current = Instant::now();
-    let contents = read_as_utf_8(&path)?;
-
-    let doc = Document::parse(&contents).unwrap();
-    debug!(
-        "It took {} ms to parse the file",
-        current.elapsed().as_millis()
-    );
-
-    let mut prices: Prices = Prices::default();
+fn set_prices_field(prices: &mut Prices, tag: &str, text: &str) -> Result<(), ParseError> {
+    match tag {
+        "XmlDocVersion" | "DllVerNo" => (),
+        "ChainId" | "ChainID" => prices.chain_id = xml::text_to_chain_id(tag, text)?,
+        "SubChainId" | "SubChainID" => prices.subchain_id = xml::text_to_i32(tag, text)?,
+        "StoreId" | "StoreID" => prices.store_id = xml::text_to_i32(tag, text)?,
+        "BikoretNo" => prices.verification_num = xml::text_to_i32(tag, text)?,
+        _ => Err(ParseError::UnknownTag {
+            tag: tag.to_string(),
+            parent: "Prices".to_string(),
+        })?,
+    }
+    Ok(())
+}
 
-    for elem in doc
-        .descendants()
-        .find(|n| {
-            n.tag_name().name() == "Prices"
-                || n.tag_name().name().to_lowercase() == "root"
-                || n.tag_name().name() == "Envelope"
-        })
-        .unwrap()
-        .children()
-        .filter(Node::is_element)
-    {
-        match elem.tag_name().name() {
-            "XmlDocVersion" | "DllVerNo" => (),
-            "Items" | "Products" | "Header" => (),
-            "ChainId" | "ChainID" => prices.chain_id = xml::to_chain_id(&elem)?,
-            "SubChainId" | "SubChainID" => prices.subchain_id = xml::to_i32(&elem)?,
-            "StoreId" | "StoreID" => prices.store_id = xml::to_i32(&elem)?,
-            "BikoretNo" => prices.verification_num = xml::to_i32(&elem)?,
-            unknown => panic!("Unknown field: {unknown}"), // TODO: do not panic in prod
+fn set_item_field(item: &mut Item, tag: &str, text: &str) -> Result<(), ParseError> {
+    let string = || xml::text_to_string(text);
+    match tag {
+        "PriceUpdateDate" => item.price_update_date = string(),
+        "ItemCode" => item.item_code = xml::parse_number(tag, &string())?,
+        "ItemType" => item.internal_code = string() == "0",
+        "ItemName" | "ItemNm" => item.item_name = string(),
+        "ManufacturerName" | "ManufactureName" => item.manufacturer_name = string(),
+        "ManufactureCountry" => item.manufacture_country = xml::text_to_country_code(text),
+        "ManufacturerItemDescription" | "ManufactureItemDescription" => {
+            item.manufacturer_item_description = string()
         }
+        "UnitQty" => item.unit_qty = string(),
+        "Quantity" => item.quantity = string(),
+        "UnitOfMeasure" | "UnitMeasure" => item.unit_of_measure = string(),
+        "bIsWeighted" | "BisWeighted" | "blsWeighted" => item.b_is_weighted = string() == "1",
+        "QtyInPackage" => item.qty_in_package = string(),
+        "ItemPrice" => item.item_price = xml::text_to_price(tag, text)?,
+        "UnitOfMeasurePrice" => item.unit_of_measure_price = xml::text_to_price(tag, text)?,
+        "AllowDiscount" => item.allow_discount = string() == "1",
+        "ItemStatus" | "itemStatus" => item.item_status = xml::parse_number(tag, &string())?,
+        "ItemId" => item.item_id = string(),
+        "LastUpdateDate" => item.last_update_date = string(),
+        "LastUpdateTime" => item.last_update_time = string(),
+        _ => Err(ParseError::UnknownTag {
+            tag: tag.to_string(),
+            parent: "Item".to_string(),
+        })?,
     }
+    Ok(())
+}
 
-    let items = doc
-        .descendants()
-        .filter(|n| {
-            n.tag_name().name() == "Item"
-                || n.tag_name().name() == "Product"
-                || n.tag_name().name() == "Line"
-        })
-        .map(|n| {
-            let mut item = Item::default();
-            for elem in n.children().filter(Node::is_element) {
-                match elem.tag_name().name() {
-                    "PriceUpdateDate" => item.price_update_date = xml::to_string(&elem),
-                    "ItemCode" => item.item_code = xml::to_string(&elem).parse().unwrap_or(-999),
-                    "ItemType" => item.internal_code = xml::to_string(&elem) == "0",
-                    "ItemName" | "ItemNm" => item.item_name = xml::to_string(&elem),
-                    "ManufacturerName" | "ManufactureName" => {
-                        item.manufacturer_name = xml::to_string(&elem)
-                    }
-                    "ManufactureCountry" => item.manufacture_country = xml::to_country_code(&elem),
-                    "ManufacturerItemDescription" | "ManufactureItemDescription" => {
-                        item.manufacturer_item_description = xml::to_string(&elem)
-                    }
-                    "UnitQty" => item.unit_qty = xml::to_string(&elem),
-                    "Quantity" => item.quantity = xml::to_string(&elem),
-                    "UnitOfMeasure" | "UnitMeasure" => item.unit_of_measure = xml::to_string(&elem),
-                    "bIsWeighted" | "BisWeighted" | "blsWeighted" => {
-                        item.b_is_weighted = xml::to_string(&elem) == "1"
-                    }
-                    "QtyInPackage" => item.qty_in_package = xml::to_string(&elem),
-                    "ItemPrice" => item.item_price = xml::to_string(&elem),
-                    "UnitOfMeasurePrice" => item.unit_of_measure_price = xml::to_string(&elem),
-                    "AllowDiscount" => item.allow_discount = xml::to_string(&elem) == "1",
-                    "ItemStatus" | "itemStatus" => {
-                        item.item_status = xml::to_string(&elem).parse().unwrap()
-                    }
-                    "ItemId" => item.item_id = xml::to_string(&elem),
-                    "LastUpdateDate" => item.last_update_date = xml::to_string(&elem),
-                    "LastUpdateTime" => item.last_update_time = xml::to_string(&elem),
-                    unknown => bail!("Unknown field: {unknown}"), // TODO: do not panic in prod
+// Reads a price file without loading it in memory: each item is given to `on_item` as soon as
+// it is read, along with the fields of the file header read so far. The header is returned,
+// without items. Items read before an error were already given to `on_item`.
+#[instrument(skip(on_item))]
+pub fn stream_price_file(path: &str, mut on_item: impl FnMut(&Prices, Item)) -> Result<Prices> {
+    let current = Instant::now();
+    let mut reader = quick_xml::Reader::from_reader(open_as_utf_8(path)?);
+    let invalid_xml = |e: quick_xml::Error| ParseError::InvalidXml(e.to_string());
+
+    let mut prices = Prices::default();
+    let mut item: Option<Item> = None;
+    let mut first_tags: Vec<String> = Vec::new();
+    let (mut depth, mut root_depth, mut item_depth) = (0, None, None);
+    // The tag and text of the header or item field being read, with its depth.
+    let mut field: Option<(String, usize)> = None;
+    let mut text = String::new();
+    let mut buf = Vec::new();
+    loop {
+        let event = reader.read_event_into(&mut buf).map_err(invalid_xml)?;
+        let (start, end) = match &event {
+            Event::Start(e) => (Some(e.local_name()), false),
+            Event::Empty(e) => (Some(e.local_name()), true),
+            Event::End(_) => (None, true),
+            Event::Text(e) if field.is_some() => {
+                text.push_str(&e.unescape().map_err(invalid_xml)?);
+                (None, false)
+            }
+            Event::CData(e) if field.is_some() => {
+                let cdata =
+                    std::str::from_utf8(e).map_err(|e| ParseError::BadEncoding(e.to_string()))?;
+                text.push_str(cdata);
+                (None, false)
+            }
+            Event::Eof => break,
+            _ => (None, false),
+        };
+        if let Some(name) = start {
+            let name = std::str::from_utf8(name.as_ref())
+                .map_err(|e| ParseError::BadEncoding(e.to_string()))?;
+            if first_tags.len() < 20 {
+                first_tags.push(name.to_string());
+            }
+            depth += 1;
+            if root_depth.is_none() {
+                if name == "Prices" || name.to_lowercase() == "root" || name == "Envelope" {
+                    root_depth = Some(depth);
                 }
+            } else if item_depth.is_none() && matches!(name, "Item" | "Product" | "Line") {
+                item_depth = Some(depth);
+                item = Some(Item::default());
+            } else if item_depth.is_some_and(|d| d + 1 == depth)
+                || (item_depth.is_none()
+                    && root_depth.is_some_and(|d| d + 1 == depth)
+                    && !matches!(name, "Items" | "Products" | "Header"))
+            {
+                field = Some((name.to_string(), depth));
+                text.clear();
             }
-            Ok(item)
-        });
-    for item in items {
-        let i = item?;
-        prices.items.push(i);
+        }
+        if end {
+            if let Some((tag, _)) = field.take_if(|(_, d)| *d == depth) {
+                match item.as_mut() {
+                    Some(item) => set_item_field(item, &tag, &text)?,
+                    None => set_prices_field(&mut prices, &tag, &text)?,
+                }
+            }
+            if item_depth == Some(depth) {
+                item_depth = None;
+                if let Some(item) = item.take() {
+                    on_item(&prices, item);
+                }
+            }
+            depth -= 1;
+        }
+        buf.clear();
+    }
+    if root_depth.is_none() {
+        Err(ParseError::UnknownRoot(first_tags.join(", ")))?;
     }
+    debug!(
+        "It took {} ms to parse the file",
+        current.elapsed().as_millis()
+    );
+    Ok(prices)
+}
+
+#[instrument]
+pub fn hande_price_file(path: &str) -> Result<Prices> {
+    let mut items = Vec::new();
+    let mut prices = stream_price_file(path, |_, item| items.push(item))?;
+    prices.items = items;
 
     prices.items.sort_by_key(|i| i.item_code);
     prices.items.dedup_by_key(|i| i.item_code);
@@ -162,10 +242,142 @@ pub fn hande_price_file(path: &str) -> Result<Prices> {
     Ok(prices)
 }
 
-fn get_chain_from_asx_values(node: Node, path: &str) -> Result<Chain> {
+// Some chains list each promotion once with all its items, while others (e.g. MatrixCatalog)
+// have one element per item and repeat the promotion data.
+fn to_promotion(node: &Node) -> Result<Promotion> {
+    let mut promotion = Promotion::default();
+    let (mut start_date, mut start_hour) = (String::new(), String::new());
+    let (mut end_date, mut end_hour) = (String::new(), String::new());
+    let mut is_gift_item = false;
+    let mut item_code = None;
+
+    for elem in node.children().filter(Node::is_element) {
+        match elem.tag_name().name() {
+            "PromotionId" | "PromotionID" => promotion.promotion_id = xml::to_string(&elem),
+            "PromotionDescription" => promotion.description = xml::to_string(&elem),
+            "PromotionStartDate" => start_date = xml::to_string(&elem),
+            "PromotionStartHour" => start_hour = xml::to_string(&elem),
+            "PromotionEndDate" => end_date = xml::to_string(&elem),
+            "PromotionEndHour" => end_hour = xml::to_string(&elem),
+            "DiscountedPrice" => promotion.discounted_price = xml::to_price(&elem),
+            "DiscountRate" => promotion.discount_rate = xml::to_f64(&elem),
+            "MinQty" => promotion.min_qty = xml::to_f64(&elem),
+            "MaxQty" => promotion.max_qty = xml::to_f64(&elem),
+            "ClubId" | "ClubID" | "Clubs" => {
+                promotion.club_only |= elem
+                    .descendants()
+                    .filter_map(|n| n.text())
+                    .map(str::trim)
+                    .any(|club| !club.is_empty() && !club.starts_with('0'))
+            }
+            "PromotionItems" => {
+                for item in elem.children().filter(Node::is_element) {
+                    let code =
+                        xml::parse_number("ItemCode", &xml::to_child_content(&item, "ItemCode")?)?;
+                    let is_gift =
+                        xml::to_child_content(&item, "IsGiftItem").is_ok_and(|s| s == "1");
+                    match is_gift {
+                        true => promotion.gift_items.push(code),
+                        false => promotion.item_codes.push(code),
+                    }
+                }
+            }
+            "ItemCode" => item_code = Some(xml::parse_number("ItemCode", &xml::to_string(&elem))?),
+            "IsGiftItem" => is_gift_item = xml::to_string(&elem) == "1",
+            "PromotionUpdateDate" | "PriceUpdateDate" | "RewardType" => (),
+            "AllowMultipleDiscounts" | "DiscountType" | "DiscountedPricePerMida" => (),
+            "MinPurchaseAmnt" | "MinNoOfItemOfered" | "MinNoOfItemsOffered" => (),
+            "MaxNoOfItemsOffered" => (),
+            "AdditionalRestrictions" | "AdditionalIsCoupon" | "AdditionalGiftCount" => (),
+            "AdditionalIsTotal" | "AdditionalIsActive" | "AdditionalMinAmount" => (),
+            "AdditionalMinBasketAmount" | "Remarks" | "Remark" => (),
+            "ItemType" | "IsWeighted" | "IsWeightedPromo" | "bIsWeighted" => (),
+            _ => Err(xml::unknown_tag(&elem))?,
+        }
+    }
+    if let Some(code) = item_code {
+        match is_gift_item {
+            true => promotion.gift_items.push(code),
+            false => promotion.item_codes.push(code),
+        }
+    }
+    promotion.start = xml::to_datetime(&start_date, &start_hour);
+    promotion.end = xml::to_datetime(&end_date, &end_hour);
+    Ok(promotion)
+}
+
+fn parse_promotions(contents: &str) -> Result<Promotions> {
+    let doc = parse_document(contents)?;
+
+    let mut promotions = Promotions::default();
+    let root = doc
+        .descendants()
+        .find(|n| {
+            n.tag_name().name() == "Promos"
+                || n.tag_name().name().to_lowercase() == "root"
+                || n.tag_name().name() == "Envelope"
+        })
+        .ok_or(ParseError::UnknownRoot(first_tags(&doc)))?;
+
+    // Super-Pharm puts the store identifiers inside a Header tag.
+    let header_elems =
+        root.children()
+            .filter(Node::is_element)
+            .flat_map(|elem| match elem.tag_name().name() {
+                "Header" => elem.children().filter(Node::is_element).collect::<Vec<_>>(),
+                _ => vec![elem],
+            });
+    for elem in header_elems {
+        match elem.tag_name().name() {
+            "XmlDocVersion" | "DllVerNo" => (),
+            "Promotions" | "Sales" | "Details" => (),
+            "ChainId" | "ChainID" => promotions.chain_id = xml::to_chain_id(&elem)?,
+            "SubChainId" | "SubChainID" => promotions.subchain_id = xml::to_i32(&elem)?,
+            "StoreId" | "StoreID" => promotions.store_id = xml::to_i32(&elem)?,
+            "BikoretNo" => promotions.verification_num = xml::to_i32(&elem)?,
+            _ => Err(xml::unknown_tag(&elem))?,
+        }
+    }
+
+    let mut indices: HashMap<String, usize> = HashMap::new();
+    for node in root.descendants().filter(|n| {
+        n.tag_name().name() == "Promotion"
+            || n.tag_name().name() == "Sale"
+            || n.tag_name().name() == "Line"
+    }) {
+        let promotion = to_promotion(&node)?;
+        match indices.get(&promotion.promotion_id) {
+            Some(&i) => {
+                let existing = &mut promotions.promotions[i];
+                existing.item_codes.extend(promotion.item_codes);
+                existing.gift_items.extend(promotion.gift_items);
+            }
+            None => {
+                indices.insert(promotion.promotion_id.clone(), promotions.promotions.len());
+                promotions.promotions.push(promotion);
+            }
+        }
+    }
+    for promotion in &mut promotions.promotions {
+        promotion.item_codes.sort();
+        promotion.item_codes.dedup();
+        promotion.gift_items.sort();
+        promotion.gift_items.dedup();
+    }
+
+    Ok(promotions)
+}
+
+#[instrument]
+pub fn handle_promo_file(path: &str) -> Result<Promotions> {
+    let contents = read_as_utf_8(path)?;
+    parse_promotions(&contents)
+}
+
+fn get_chain_from_asx_values(node: Node) -> Result<Chain> {
     let mut chain = Chain::default();
 
-    chain.chain_id = xml::to_child_content(&node, "CHAINID")?.parse()?;
+    chain.chain_id = xml::parse_number("CHAINID", &xml::to_child_content(&node, "CHAINID")?)?;
 
     let mut subchains: HashMap<i32, Subchain> = HashMap::new();
 
@@ -173,7 +385,7 @@ fn get_chain_from_asx_values(node: Node, path: &str) -> Result<Chain> {
         .descendants()
         .filter(|n| n.tag_name().name() == "STORE")
     {
-        let full_store = to_full_store(&elem, path)?;
+        let full_store = to_full_store(&elem)?;
 
         match subchains.get_mut(&full_store.subchain_id) {
             Some(subchain) => subchain,
@@ -197,20 +409,21 @@ fn get_chain_from_asx_values(node: Node, path: &str) -> Result<Chain> {
     Ok(chain)
 }
 
-fn get_chain_from_envelope(node: Node, path: &str) -> Result<Chain> {
+fn get_chain_from_envelope(node: Node) -> Result<Chain> {
     let mut chain = Chain::default();
-    chain.chain_id = xml::to_child_content(&node, "ChainId")?.parse()?;
+    chain.chain_id = xml::parse_number("ChainId", &xml::to_child_content(&node, "ChainId")?)?;
 
     let mut subchain = Subchain::default();
-    subchain.subchain_id = xml::to_child_content(&node, "SubChainId")?.parse().unwrap();
+    subchain.subchain_id =
+        xml::parse_number("SubChainId", &xml::to_child_content(&node, "SubChainId")?)?;
 
     for line in node.descendants().filter(|n| n.tag_name().name() == "Line") {
-        subchain.stores.push(to_full_store(&line, path)?.store);
+        subchain.stores.push(to_full_store(&line)?.store);
     }
     chain.subchains.push(subchain);
     Ok(chain)
 }
-fn get_chain_from_stores(node: Node, path: &str) -> Result<Chain> {
+fn get_chain_from_stores(node: Node) -> Result<Chain> {
     let mut chain = Chain::default();
 
     let mut subchains: HashMap<i32, Subchain> = HashMap::new();
@@ -218,7 +431,7 @@ fn get_chain_from_stores(node: Node, path: &str) -> Result<Chain> {
         .descendants()
         .filter(|n| n.tag_name().name() == "Branch")
     {
-        let full_store = to_full_store(&branch, path)?;
+        let full_store = to_full_store(&branch)?;
         let subchain = subchains
             .entry(full_store.subchain_id)
             .or_insert_with(|| Subchain {
@@ -233,7 +446,7 @@ fn get_chain_from_stores(node: Node, path: &str) -> Result<Chain> {
     chain.subchains.extend(subchains.into_values());
     Ok(chain)
 }
-fn get_chain_from_root(root: Node, path: &str) -> Result<Chain> {
+fn get_chain_from_root(root: Node) -> Result<Chain> {
     let mut chain = Chain::default();
     for elem in root.children().filter(Node::is_element) {
         match elem.tag_name().name() {
@@ -242,7 +455,7 @@ fn get_chain_from_root(root: Node, path: &str) -> Result<Chain> {
             "SubChains" => (),
             "ChainId" => chain.chain_id = xml::to_chain_id(&elem)?,
             "ChainName" => chain.chain_name = xml::to_string(&elem),
-            unknown => panic!("Unknown field: {unknown} in file {path}"), // TODO: do not panic in prod
+            _ => Err(xml::unknown_tag(&elem))?,
         }
     }
 
@@ -256,7 +469,7 @@ fn get_chain_from_root(root: Node, path: &str) -> Result<Chain> {
                 "Stores" => (),
                 "SubChainId" => subchain.subchain_id = xml::to_i32(&elem)?,
                 "SubChainName" => subchain.subchain_name = xml::to_string(&elem),
-                unknown => panic!("Unknown field: {unknown} in file {path}"), // TODO: do not panic in prod
+                _ => Err(xml::unknown_tag(&elem))?,
             };
         }
 
@@ -264,7 +477,7 @@ fn get_chain_from_root(root: Node, path: &str) -> Result<Chain> {
             .descendants()
             .filter(|n| n.tag_name().name() == "Store")
         {
-            subchain.stores.push(to_full_store(&store, path)?.store);
+            subchain.stores.push(to_full_store(&store)?.store);
         }
 
         chain.subchains.push(subchain);
@@ -276,26 +489,19 @@ fn get_chain_from_root(root: Node, path: &str) -> Result<Chain> {
 pub fn handle_stores_file(path: &str) -> Result<Chain> {
     let contents = read_as_utf_8(path)?;
 
-    let doc = Document::parse(&contents).unwrap();
+    let doc = parse_document(&contents)?;
 
     let mut chain = {
         if let Some(node) = xml::get_descendant(&doc, "Root") {
-            get_chain_from_root(node, path)?
+            get_chain_from_root(node)?
         } else if let Some(node) = xml::get_descendant(&doc, "Store") {
-            get_chain_from_stores(node, path)?
+            get_chain_from_stores(node)?
         } else if let Some(node) = xml::get_descendant(&doc, "Envelope") {
-            get_chain_from_envelope(node, path)?
+            get_chain_from_envelope(node)?
         } else if let Some(node) = xml::get_descendant(&doc, "values") {
-            get_chain_from_asx_values(node, path)?
+            get_chain_from_asx_values(node)?
         } else {
-            let x = doc
-                .descendants()
-                .into_iter()
-                .take(20)
-                .filter(Node::is_element)
-                .map(|x| x.tag_name().name().to_string() + ", ")
-                .collect::<String>();
-            panic!("{}; root: {:?}", path, x);
+            Err(ParseError::UnknownRoot(first_tags(&doc)))?
         }
     };
 
@@ -305,10 +511,18 @@ pub fn handle_stores_file(path: &str) -> Result<Chain> {
     for subchain in &mut chain.subchains {
         subchain.stores.sort_by(|x, y| x.store_id.cmp(&y.store_id));
     }
-    validate_chain(&chain);
+    validate_chain(&chain)?;
     Ok(chain)
 }
 
+fn first_tags(doc: &Document) -> String {
+    doc.descendants()
+        .filter(Node::is_element)
+        .take(20)
+        .map(|x| x.tag_name().name())
+        .join(", ")
+}
+
 fn read_as_utf_8(path: &str) -> Result<String> {
     let span = span!(Level::DEBUG, "read_as_utf_8", path = path,);
     let _enter = span.enter();
@@ -321,18 +535,21 @@ fn read_as_utf_8(path: &str) -> Result<String> {
         debug!("Using encoding utf-8");
         return Ok(s);
     }
-    let (first_line, _, _) = encoding_rs::UTF_8.decode(&buf[..80]);
+    let (first_line, _, _) = encoding_rs::UTF_8.decode(&buf[..buf.len().min(80)]);
     if first_line.contains("encoding") {
         debug!("Found encoding in first line of file");
         let encoding = first_line
             .split_whitespace()
             .find(|s| s.starts_with("encoding"))
             .and_then(|s| s.rsplit_once('='))
-            .ok_or(anyhow!("Counldn't exract encoding"))?
+            .ok_or(ParseError::BadEncoding(
+                "couldn't extract the encoding".to_string(),
+            ))?
             .1
             .trim_matches('"');
-        let encoding = encoding_rs::Encoding::for_label(encoding.as_bytes())
-            .ok_or(anyhow!("Couldn't find encoding for {encoding}"))?;
+        let encoding = encoding_rs::Encoding::for_label(encoding.as_bytes()).ok_or(
+            ParseError::BadEncoding(format!("unknown encoding {encoding}")),
+        )?;
 
         debug!("Found encoding {}", encoding.name());
         let (decoded, _, _) = encoding.decode(&buf);
@@ -343,3 +560,268 @@ fn read_as_utf_8(path: &str) -> Result<String> {
     let (decoded, _, _) = UTF_16LE.decode(&buf);
     Ok(decoded.into_owned())
 }
+
+// Whether the whole file is valid utf-8, read by chunks so that it isn't held in memory.
+fn is_utf_8(path: &str) -> Result<bool> {
+    let mut file = std::fs::File::open(path)?;
+    let mut buf = vec![0; 1 << 16];
+    // The bytes of a character cut at the end of the previous chunk.
+    let mut pending = 0;
+    loop {
+        let read = file.read(&mut buf[pending..])?;
+        if read == 0 {
+            return Ok(pending == 0);
+        }
+        let len = pending + read;
+        pending = match std::str::from_utf8(&buf[..len]) {
+            Ok(_) => 0,
+            Err(e) if e.error_len().is_none() => {
+                buf.copy_within(e.valid_up_to()..len, 0);
+                len - e.valid_up_to()
+            }
+            Err(_) => return Ok(false),
+        };
+    }
+}
+
+// Like read_as_utf_8, but decodes the file while it is read: utf-8 is used when the whole file is
+// valid utf-8, whatever encoding it declares, and otherwise the declared encoding or utf-16.
+fn open_as_utf_8(path: &str) -> Result<impl BufRead> {
+    let valid_utf_8 = is_utf_8(path)?;
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let start = file.fill_buf()?;
+    let encoding = if start.len() >= 2 && start[0] == b'<' && start[1] == 0 {
+        UTF_16LE
+    } else if valid_utf_8 {
+        encoding_rs::UTF_8
+    } else {
+        let (first_line, _, _) = encoding_rs::UTF_8.decode(&start[..start.len().min(80)]);
+        match first_line
+            .split_whitespace()
+            .find(|s| s.starts_with("encoding"))
+            .and_then(|s| s.rsplit_once('='))
+            .map(|s| {
+                s.1.trim_matches(|c| c == '"' || c == '\'' || c == '?' || c == '>')
+            }) {
+            Some(label) => encoding_rs::Encoding::for_label(label.as_bytes())
+                .ok_or(ParseError::BadEncoding(format!("unknown encoding {label}")))?,
+            None => UTF_16LE,
+        }
+    };
+    debug!("Using encoding {}", encoding.name());
+    let decoder = encoding_rs_io::DecodeReaderBytesBuilder::new()
+        .encoding(Some(encoding))
+        .bom_override(true)
+        .strip_bom(true)
+        .build(file);
+    Ok(std::io::BufReader::new(decoder))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::price::Price;
+
+    #[test]
+    fn test_parse_promotions_with_promotion_items() {
+        let promotions = parse_promotions(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<Root>
+  <ChainId>7290058140886</ChainId>
+  <SubChainId>001</SubChainId>
+  <StoreId>039</StoreId>
+  <BikoretNo>9</BikoretNo>
+  <DllVerNo>8.0.1.3</DllVerNo>
+  <Promotions Count="1">
+    <Promotion>
+      <PromotionId>1234</PromotionId>
+      <PromotionDescription>2 ב-10</PromotionDescription>
+      <PromotionUpdateDate>2023-05-01 10:00</PromotionUpdateDate>
+      <PromotionStartDate>2023-05-01</PromotionStartDate>
+      <PromotionStartHour>08:00</PromotionStartHour>
+      <PromotionEndDate>2023-05-31</PromotionEndDate>
+      <PromotionEndHour>23:59</PromotionEndHour>
+      <RewardType>1</RewardType>
+      <MinQty>2.00</MinQty>
+      <DiscountedPrice>10.00</DiscountedPrice>
+      <PromotionItems Count="2">
+        <Item><ItemCode>7290000000001</ItemCode><ItemType>1</ItemType><IsGiftItem>0</IsGiftItem></Item>
+        <Item><ItemCode>7290000000002</ItemCode><ItemType>1</ItemType><IsGiftItem>1</IsGiftItem></Item>
+      </PromotionItems>
+      <Clubs><ClubId>2</ClubId></Clubs>
+    </Promotion>
+  </Promotions>
+</Root>"#,
+        )
+        .unwrap();
+        assert_eq!(promotions.chain_id, 7290058140886);
+        assert_eq!(promotions.store_id, 39);
+        assert_eq!(promotions.promotions.len(), 1);
+        let promotion = &promotions.promotions[0];
+        assert_eq!(promotion.promotion_id, "1234");
+        assert_eq!(promotion.discounted_price, Some(Price(1000)));
+        assert_eq!(promotion.min_qty, Some(2.0));
+        assert!(promotion.club_only);
+        assert_eq!(promotion.item_codes, vec![7290000000001]);
+        assert_eq!(promotion.gift_items, vec![7290000000002]);
+        assert_eq!(promotion.start, xml::to_datetime("2023-05-01", "08:00"));
+        assert!(promotion.end.is_some());
+    }
+
+    #[test]
+    fn test_parse_promotions_with_one_sale_per_item() {
+        let promotions = parse_promotions(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<Promos>
+  <ChainID>7290696200003</ChainID>
+  <SubChainID>1</SubChainID>
+  <StoreID>1</StoreID>
+  <BikoretNo>5</BikoretNo>
+  <Sales>
+    <Sale>
+      <ItemCode>7290000000001</ItemCode>
+      <ItemType>1</ItemType>
+      <PromotionID>77</PromotionID>
+      <PromotionDescription>מבצע</PromotionDescription>
+      <PromotionStartDate>2023/05/01</PromotionStartDate>
+      <PromotionStartHour>00:00:00</PromotionStartHour>
+      <PromotionEndDate>2023/05/07</PromotionEndDate>
+      <PromotionEndHour>23:59:00</PromotionEndHour>
+      <DiscountRate>20</DiscountRate>
+      <Clubs>0</Clubs>
+    </Sale>
+    <Sale>
+      <ItemCode>7290000000003</ItemCode>
+      <ItemType>1</ItemType>
+      <PromotionID>77</PromotionID>
+      <PromotionDescription>מבצע</PromotionDescription>
+      <PromotionStartDate>2023/05/01</PromotionStartDate>
+      <PromotionStartHour>00:00:00</PromotionStartHour>
+      <PromotionEndDate>2023/05/07</PromotionEndDate>
+      <PromotionEndHour>23:59:00</PromotionEndHour>
+      <DiscountRate>20</DiscountRate>
+      <Clubs>0</Clubs>
+    </Sale>
+  </Sales>
+</Promos>"#,
+        )
+        .unwrap();
+        assert_eq!(promotions.chain_id, 7290696200003);
+        assert_eq!(promotions.promotions.len(), 1);
+        let promotion = &promotions.promotions[0];
+        assert!(!promotion.club_only);
+        assert_eq!(promotion.discount_rate, Some(20.0));
+        assert_eq!(promotion.item_codes, vec![7290000000001, 7290000000003]);
+        assert_eq!(promotion.end, xml::to_datetime("2023-05-07 23:59:00", ""));
+    }
+
+    #[test]
+    fn test_stream_price_file() {
+        let contents = r#"<?xml version="1.0" encoding="utf-8"?>
+<Root>
+  <ChainId>7290027600007</ChainId>
+  <SubChainId>001</SubChainId>
+  <StoreId>039</StoreId>
+  <BikoretNo>9</BikoretNo>
+  <Items Count="2">
+    <Item>
+      <ItemCode>7290000000002</ItemCode>
+      <ItemType>1</ItemType>
+      <ItemName>חלב &amp; שוקו</ItemName>
+      <ItemPrice>5.90</ItemPrice>
+      <ManufactureCountry/>
+    </Item>
+    <Item>
+      <ItemCode>7290000000001</ItemCode>
+      <ItemName><![CDATA[שמנת חמוצה 15%]]></ItemName>
+      <ItemPrice>4.50</ItemPrice>
+    </Item>
+  </Items>
+</Root>"#;
+        let dir = std::env::temp_dir();
+        let utf_8_path = dir.join("test_stream_price_file_utf_8.xml");
+        std::fs::write(&utf_8_path, contents).unwrap();
+        let utf_16_path = dir.join("test_stream_price_file_utf_16.xml");
+        let utf_16 = contents
+            .replace("utf-8", "utf-16")
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect::<Vec<u8>>();
+        std::fs::write(&utf_16_path, utf_16).unwrap();
+        // Some chains declare an encoding other than the one they write.
+        let mislabeled_path = dir.join("test_stream_price_file_mislabeled.xml");
+        std::fs::write(&mislabeled_path, contents.replace("utf-8", "windows-1255")).unwrap();
+
+        for path in [utf_8_path, utf_16_path, mislabeled_path] {
+            let path = path.to_str().unwrap();
+            let mut streamed = Vec::new();
+            let header = stream_price_file(path, |header, item| {
+                streamed.push((header.store_id, item.item_code))
+            })
+            .unwrap();
+            assert_eq!(header.chain_id, 7290027600007);
+            assert!(header.items.is_empty());
+            assert_eq!(streamed, vec![(39, 7290000000002), (39, 7290000000001)]);
+
+            let prices = hande_price_file(path).unwrap();
+            assert_eq!(prices.items.len(), 2);
+            assert_eq!(prices.items[0].item_name, "שמנת חמוצה 15%");
+            assert_eq!(prices.items[1].item_name, "חלב & שוקו");
+            assert_eq!(prices.items[1].item_price, Some(Price(590)));
+        }
+    }
+
+    #[test]
+    fn test_parse_errors_are_reported() {
+        let error =
+            parse_promotions("<Root><ChainId>1</ChainId><Unexpected /></Root>").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ParseError>(),
+            Some(&ParseError::UnknownTag {
+                tag: "Unexpected".to_string(),
+                parent: "Root".to_string()
+            })
+        );
+        let error = parse_promotions("<Root><ChainId>abc</ChainId></Root>").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ParseError>().unwrap().kind(),
+            "bad_number"
+        );
+        let parse_item = |item: &str| {
+            let path = std::env::temp_dir().join("test_parse_errors_are_reported.xml");
+            std::fs::write(
+                &path,
+                format!("<Root><ChainId>1</ChainId><Items><Item>{item}</Item></Items></Root>"),
+            )
+            .unwrap();
+            hande_price_file(path.to_str().unwrap())
+        };
+        for item in [
+            "<ItemCode>72900000000a1</ItemCode>",
+            "<ItemCode>1</ItemCode><ItemPrice>abc</ItemPrice>",
+            "<ItemCode>1</ItemCode><UnitOfMeasurePrice>1.2.3</UnitOfMeasurePrice>",
+        ] {
+            let error = parse_item(item).unwrap_err();
+            assert_eq!(
+                error.downcast_ref::<ParseError>().unwrap().kind(),
+                "bad_number",
+                "{item}"
+            );
+        }
+        let prices =
+            parse_item("<ItemCode>1</ItemCode><ItemPrice></ItemPrice><UnitOfMeasurePrice>0</UnitOfMeasurePrice>")
+                .unwrap();
+        assert_eq!(prices.items[0].item_price, None);
+        assert_eq!(prices.items[0].unit_of_measure_price, None);
+        let error = parse_promotions("<Nothing />").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ParseError>().unwrap().kind(),
+            "unknown_root"
+        );
+        let error = parse_promotions("<Root>").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<ParseError>().unwrap().kind(),
+            "invalid_xml"
+        );
+    }
+}